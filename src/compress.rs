@@ -0,0 +1,113 @@
+//! Compressing and decompressing message bodies according to their content encoding.
+use flate2::{
+    read::{GzDecoder, GzEncoder, ZlibDecoder},
+    Compression as Level,
+};
+use std::{io::Read, str::FromStr};
+
+/// How to compress published bodies, along with the level to compress them at.
+#[derive(Clone, Copy)]
+pub enum Compression {
+    /// Gzip, at a level from 0 to 9.
+    Gzip(u32),
+
+    /// Zstandard, at a level from 1 to 22.
+    Zstd(i32),
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(compression: &str) -> Result<Self, Self::Err> {
+        let (algorithm, level) = match compression.split_once(':') {
+            Some((algorithm, level)) => (algorithm, Some(level)),
+            None => (compression, None),
+        };
+        let invalid = || format!("invalid compression level: {compression}");
+        match (algorithm, level) {
+            ("gzip", None) => Ok(Self::Gzip(Level::default().level())),
+            ("gzip", Some(level)) => match level.parse() {
+                Ok(level) if level <= Level::best().level() => Ok(Self::Gzip(level)),
+                _ => Err(invalid()),
+            },
+            ("zstd", None) => Ok(Self::Zstd(zstd::DEFAULT_COMPRESSION_LEVEL)),
+            ("zstd", Some(level)) => match level.parse() {
+                Ok(level) if (1..=*zstd::compression_level_range().end()).contains(&level) => {
+                    Ok(Self::Zstd(level))
+                }
+                _ => Err(invalid()),
+            },
+            _ => Err(format!("unknown compression: {compression}")),
+        }
+    }
+}
+
+impl Compression {
+    /// The content encoding of bodies compressed this way.
+    pub fn encoding(self) -> &'static str {
+        match self {
+            Self::Gzip(_) => "gzip",
+            Self::Zstd(_) => "zstd",
+        }
+    }
+
+    /// Compresses the body.
+    pub fn compress(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Self::Gzip(level) => {
+                let mut compressed = vec![];
+                GzEncoder::new(data, Level::new(level))
+                    .read_to_end(&mut compressed)
+                    .unwrap();
+                compressed
+            }
+            Self::Zstd(level) => zstd::encode_all(data, level).unwrap(),
+        }
+    }
+}
+
+/// Decompresses the body if its content encoding is gzip, deflate or zstd, giving none for other encodings.
+pub fn decompress(encoding: &str, data: &[u8]) -> Option<Result<Vec<u8>, String>> {
+    let mut decompressed = vec![];
+    let result = match encoding {
+        "gzip" | "x-gzip" => GzDecoder::new(data).read_to_end(&mut decompressed),
+        "deflate" => ZlibDecoder::new(data).read_to_end(&mut decompressed),
+        "zstd" => zstd::stream::copy_decode(data, &mut decompressed).map(|()| 0),
+        _ => return None,
+    };
+    Some(
+        result
+            .map(|_| decompressed)
+            .map_err(|err| format!("cannot decompress {encoding} body: {err}")),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decompress, Compression};
+
+    #[test]
+    fn parses_compressions() {
+        assert!(matches!("gzip".parse(), Ok(Compression::Gzip(6))));
+        assert!(matches!("gzip:0".parse(), Ok(Compression::Gzip(0))));
+        assert!(matches!("gzip:9".parse(), Ok(Compression::Gzip(9))));
+        assert!(matches!("zstd".parse(), Ok(Compression::Zstd(3))));
+        assert!(matches!("zstd:22".parse(), Ok(Compression::Zstd(22))));
+        for invalid in [
+            "gzip:10", "gzip:-1", "zstd:0", "zstd:23", "zstd:", "brotli", "gzip9",
+        ] {
+            assert!(invalid.parse::<Compression>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn decompresses_what_it_compresses() {
+        let body = b"hello hello hello hello";
+        for compression in ["gzip", "zstd:19"] {
+            let compression: Compression = compression.parse().unwrap();
+            let compressed = compression.compress(body);
+            let decompressed = decompress(compression.encoding(), &compressed).unwrap();
+            assert_eq!(decompressed.unwrap(), body);
+        }
+    }
+}
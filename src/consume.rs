@@ -0,0 +1,1206 @@
+//! Consuming messages from rabbitmq.
+use crate::{
+    exec::{exec, Done, Pool},
+    field_table,
+    filter::{FilterOpts, Glob},
+    management::ManagementOpts,
+    output::{header, Error, OutputOpts},
+    parse_arg,
+    rotate::parse_duration,
+    stats::{Latency, Progress, Stats},
+    QueueOpts,
+};
+use amq_protocol_types::{AMQPValue, FieldTable, ShortString};
+use core::time::Duration;
+use futures_lite::stream::StreamExt;
+use lapin::{
+    acker::Acker,
+    message::Delivery,
+    options::{
+        BasicAckOptions, BasicCancelOptions, BasicConsumeOptions, BasicNackOptions,
+        BasicPublishOptions, BasicQosOptions, BasicRejectOptions, ConfirmSelectOptions,
+        QueueBindOptions, QueueDeclareOptions,
+    },
+    protocol::constants::REPLY_SUCCESS,
+    Channel, ChannelState, Connection, ConnectionProperties, Consumer,
+};
+use serde::Deserialize;
+use std::{
+    borrow::Cow,
+    cell::Cell,
+    num::NonZeroUsize,
+    path::PathBuf,
+    str::FromStr,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+use structopt::StructOpt;
+use tokio::sync::mpsc;
+
+/// Options for consuming messages from a queue.
+#[derive(StructOpt)]
+pub struct Consume {
+    /// The queues from which to read, where names with * or ? are matched against those listed by the management api.
+    #[structopt(required = true)]
+    queues: Vec<String>,
+
+    /// Whether to declare the queues before consuming from them.
+    #[structopt(long)]
+    declare: bool,
+
+    #[structopt(flatten)]
+    management: ManagementOpts,
+
+    #[structopt(flatten)]
+    queue_opts: QueueOpts,
+
+    #[structopt(flatten)]
+    opts: ConsumeOpts,
+}
+
+impl Consume {
+    /// Optionally declares the queues and then consumes from them, giving the exit status.
+    pub async fn run(self, addr: &str, conn: &Connection, chan: Channel) -> i32 {
+        let queues = self.queues();
+        if self.declare {
+            for queue in &queues {
+                self.queue_opts
+                    .clone()
+                    .declare(&chan, queue, QueueDeclareOptions::default())
+                    .await;
+            }
+        }
+        self.opts.run(addr, conn, chan, &queues).await
+    }
+
+    /// Expands queue name patterns into the matching queue names.
+    fn queues(&self) -> Vec<String> {
+        /// A queue as listed by the management api.
+        #[derive(Deserialize)]
+        struct Listed {
+            name: String,
+        }
+        let mut listed = None;
+        let mut queues = vec![];
+        for queue in &self.queues {
+            if !queue.contains(['*', '?']) {
+                queues.push(queue.clone());
+                continue;
+            }
+            let glob: Glob = queue.parse().unwrap();
+            let listed: &Vec<Listed> = listed.get_or_insert_with(|| self.management.get("queues"));
+            queues.extend(
+                listed
+                    .iter()
+                    .filter(|listed| glob.matches(&listed.name))
+                    .map(|listed| listed.name.clone()),
+            );
+        }
+        assert!(!queues.is_empty(), "no queues match {:?}", self.queues);
+        queues
+    }
+}
+
+/// Options for consuming messages from an exchange via a temporary queue.
+#[derive(StructOpt)]
+pub struct Subscribe {
+    /// The exchange to subscribe to.
+    exchange: String,
+
+    /// Routing key or pattern to bind with, may be repeated.
+    #[structopt(short, long = "routing-key", number_of_values = 1)]
+    routing_keys: Vec<String>,
+
+    #[structopt(flatten)]
+    opts: ConsumeOpts,
+}
+
+impl Subscribe {
+    /// Declares an exclusive server named queue, binds it and consumes from it, giving the exit status.
+    pub async fn run(self, addr: &str, conn: &Connection, chan: Channel) -> i32 {
+        let queue = chan
+            .queue_declare(
+                "",
+                QueueDeclareOptions {
+                    exclusive: true,
+                    auto_delete: true,
+                    ..QueueDeclareOptions::default()
+                },
+                FieldTable::default(),
+            )
+            .await
+            .unwrap();
+        let routing_keys = if self.routing_keys.is_empty() {
+            vec![String::new()]
+        } else {
+            self.routing_keys
+        };
+        for routing_key in routing_keys {
+            chan.queue_bind(
+                queue.name().as_str(),
+                &self.exchange,
+                &routing_key,
+                QueueBindOptions::default(),
+                FieldTable::default(),
+            )
+            .await
+            .unwrap();
+        }
+        self.opts
+            .run(addr, conn, chan, &[queue.name().to_string()])
+            .await
+    }
+}
+
+/// Options controlling how messages are consumed and written out.
+#[derive(StructOpt)]
+#[allow(clippy::struct_excessive_bools)]
+struct ConsumeOpts {
+    /// Identifies the consumers, suffixed with the index of their queue when consuming from several.
+    #[structopt(short, long, default_value = "")]
+    consumer_tag: String,
+
+    /// What to do with messages containing the delimiter, newlines by default.
+    #[structopt(long, default_value = "reject", possible_values = POLICIES)]
+    on_newline: Policy,
+
+    /// What to do with messages which cannot be decompressed, decoded or parsed as utf-8, or as json with --jq.
+    #[structopt(long, default_value = "reject", possible_values = POLICIES)]
+    on_invalid_utf8: Policy,
+
+    /// What to do with messages which are filtered out.
+    #[structopt(long, default_value = "ack", possible_values = POLICIES)]
+    on_mismatch: Policy,
+
+    /// Shell command to pipe each body to instead of writing it out, with the delivery attributes in `AMQP_*` variables.
+    #[structopt(long)]
+    exec: Option<String>,
+
+    /// Number of commands to run at once, messages are then acknowledged individually as their commands finish.
+    #[structopt(long, default_value = "1")]
+    workers: NonZeroUsize,
+
+    /// What to do with messages for which the command fails.
+    #[structopt(long, default_value = "reject", possible_values = POLICIES)]
+    on_exec_error: Policy,
+
+    /// Exchange to republish messages which fail to, with the error in their headers, instead of settling them by policy.
+    #[structopt(long)]
+    error_exchange: Option<String>,
+
+    /// Routing key for messages republished to the error exchange, defaults to their original one.
+    #[structopt(long, requires = "error-exchange")]
+    error_routing_key: Option<String>,
+
+    /// Retries failed messages this many times via delay queues named like `QUEUE.retry.1`, then parks them in `QUEUE.dlq`.
+    #[structopt(long, conflicts_with = "error-exchange")]
+    max_retries: Option<u32>,
+
+    /// Milliseconds before the first retry, doubling with each subsequent one.
+    #[structopt(long, default_value = "1000")]
+    retry_delay_ms: u32,
+
+    /// Channel in confirm mode for republishing failed messages.
+    #[structopt(skip)]
+    errors: Option<Channel>,
+
+    /// Whether rejected messages go back to the queue instead of being dropped or dead lettered.
+    #[structopt(long)]
+    requeue_on_error: bool,
+
+    /// Number of times to resume consuming after a channel or the connection closes, e.g. due to a precondition failure
+    /// or broker restart, or the broker cancels a consumer, before giving up.
+    #[structopt(long, default_value = "3")]
+    channel_retries: u32,
+
+    /// Maximum number of unacknowledged messages the broker sends at once, defaults to 512, or twice the number of
+    /// workers when there is more than one.
+    #[structopt(long)]
+    prefetch: Option<u16>,
+
+    /// Either batch, acknowledging several messages at once, or individual.
+    #[structopt(long, default_value = "batch", possible_values = &["batch", "individual"])]
+    ack_mode: AckMode,
+
+    /// Number of messages to process before acknowledging them all at once, at most the prefetch.
+    #[structopt(long, default_value = "256")]
+    ack_batch: u16,
+
+    /// Number of channels to consume each queue on, every queue having channels of its own so that acknowledging
+    /// several messages at once never covers those of another queue.
+    #[structopt(long, default_value = "1")]
+    concurrency: NonZeroUsize,
+
+    /// Exits after this many messages have been processed.
+    #[structopt(long)]
+    count: Option<u64>,
+
+    /// Exits with status 2 once no messages have arrived for this many seconds.
+    #[structopt(long)]
+    idle_timeout: Option<u64>,
+
+    /// Exits once the queues have no more ready messages.
+    #[structopt(long)]
+    drain: bool,
+
+    /// Exits cleanly once consuming for this long, e.g. 5m.
+    #[structopt(long, parse(try_from_str = parse_duration))]
+    duration: Option<Duration>,
+
+    /// Whether the broker should consider messages acknowledged as soon as they are sent.
+    #[structopt(long)]
+    no_ack: bool,
+
+    /// Whether to flush each message and only acknowledge it once written, stopping with those unwritten requeued if stdout breaks.
+    #[structopt(long, conflicts_with_all = &["no-ack", "exec"])]
+    pipe_safe: bool,
+
+    /// Set once stdout can no longer be written to in pipe safe mode.
+    #[structopt(skip)]
+    broken: Cell<bool>,
+
+    /// Prints totals of messages, bytes, acknowledgements, rejections and errors along with the rate to stderr on exit.
+    #[structopt(long)]
+    stats: bool,
+
+    /// File to write the totals to as a json object on exit instead of stderr.
+    #[structopt(long)]
+    stats_file: Option<PathBuf>,
+
+    /// Totals of the messages consumed so far.
+    #[structopt(skip)]
+    totals: Stats,
+
+    /// Prints the message and byte rates along with the number of ready messages to stderr every second.
+    #[structopt(long)]
+    progress: bool,
+
+    /// Throughput since progress was last reported, if it is.
+    #[structopt(skip)]
+    meter: Option<Progress>,
+
+    /// Prints how long after being published each message was consumed to stderr, and a histogram on exit.
+    #[structopt(long)]
+    latency: bool,
+
+    /// Header holding the unix time in milliseconds each message was published at, instead of its timestamp property.
+    #[structopt(long, requires = "latency")]
+    latency_header: Option<String>,
+
+    /// Latencies of the messages so far.
+    #[structopt(skip)]
+    latencies: Latency,
+
+    /// Whether to fail unless this is the only consumer on the queue.
+    #[structopt(long)]
+    exclusive: bool,
+
+    /// Consumers with higher priority receive messages first, the default is 0.
+    #[structopt(long)]
+    consumer_priority: Option<i32>,
+
+    /// Consumer argument in the form key=value[:type], e.g. x-stream-offset=first, may be repeated.
+    #[structopt(long = "consume-arg", number_of_values = 1, parse(try_from_str = parse_arg))]
+    consume_args: Vec<(ShortString, AMQPValue)>,
+
+    /// Reports on stderr whether this is the active consumer of a single active consumer queue.
+    #[structopt(long)]
+    report_active: bool,
+
+    #[structopt(flatten)]
+    filter: FilterOpts,
+
+    #[structopt(flatten)]
+    output: OutputOpts,
+}
+
+/// How to settle a message which has been processed.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Policy {
+    /// Acknowledges the message, removing it from the queue.
+    Ack,
+
+    /// Rejects the message, requeueing it if requested for all errors.
+    Reject,
+
+    /// Negatively acknowledges the message and puts it back on the queue.
+    NackRequeue,
+
+    /// Rejects the message without requeueing, so it goes to the dead letter exchange if any.
+    DeadLetter,
+}
+
+/// Names of the settlement policies.
+const POLICIES: &[&str] = &["ack", "reject", "nack-requeue", "dead-letter"];
+
+impl FromStr for Policy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "ack" => Ok(Self::Ack),
+            "reject" => Ok(Self::Reject),
+            "nack-requeue" => Ok(Self::NackRequeue),
+            "dead-letter" => Ok(Self::DeadLetter),
+            _ => Err(format!("unknown policy: {policy}")),
+        }
+    }
+}
+
+/// When to acknowledge messages.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum AckMode {
+    /// Acknowledges all messages processed so far every so often.
+    Batch,
+
+    /// Acknowledges each message as soon as it has been written out.
+    Individual,
+}
+
+impl FromStr for AckMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "batch" => Ok(Self::Batch),
+            "individual" => Ok(Self::Individual),
+            _ => Err(format!("unknown ack mode: {mode}")),
+        }
+    }
+}
+
+/// A delivery along with the index of the queue and channel it came from, or the error ending a consumer along with its
+/// id.
+type Forwarded = (usize, usize, Result<Delivery, (u64, lapin::Error)>);
+
+/// Deliveries from several consumers.
+type Deliveries = mpsc::UnboundedReceiver<Forwarded>;
+
+/// Header counting how many times a message has been retried.
+const RETRY_COUNT: &str = "x-retry-count";
+
+/// How a message should be settled, along with the error if it failed.
+type Outcome = Result<Policy, (Policy, String)>;
+
+/// Exit status when consumption stops due to the idle timeout.
+const IDLE_EXIT_CODE: i32 = 2;
+
+/// Prefetch unless there is a pool of workers.
+const DEFAULT_PREFETCH: u16 = 512;
+
+/// Prefetch per worker, enough to keep each busy without hoarding messages other consumers could take.
+const WORKER_PREFETCH: usize = 2;
+
+/// How long without deliveries before settling batches and checking for idleness.
+const IDLE_TICK: Duration = Duration::from_secs(1);
+
+/// Exit status when consumption stops because stdout broke in pipe safe mode.
+const WRITE_ERROR_EXIT_CODE: i32 = 1;
+
+impl ConsumeOpts {
+    /// How long to wait for a delivery before checking again, short enough to flush stdout on time.
+    fn tick(&self) -> Duration {
+        self.output
+            .flush_interval()
+            .map_or(IDLE_TICK, |interval| interval.min(IDLE_TICK))
+    }
+
+    /// Flushes stdout if it is due, giving up on a broken stdout in pipe safe mode.
+    fn flush_due(&self) {
+        match self.output.flush_due() {
+            Err(err @ Error::Write(_)) if self.pipe_safe => {
+                eprintln!("{err}");
+                self.broken.set(true);
+            }
+            result => result.unwrap(),
+        }
+    }
+
+    /// Loops through the messages until done or interrupted, then acknowledges those processed and closes the channels.
+    async fn run(mut self, addr: &str, conn: &Connection, chan: Channel, queues: &[String]) -> i32 {
+        self.open(conn, &chan, queues).await;
+        let (mut consumers, mut deliveries) = self.start(addr, conn, chan, queues).await;
+        let idle_timeout = self.idle_timeout.map(Duration::from_secs);
+        let batch = Batch::new(self.ack_limit(), consumers.channels.len(), self.pipe_safe);
+        let (mut batch, mut remaining) = (batch, self.count);
+        let (mut last, mut idle, mut active) = (Instant::now(), false, vec![None; queues.len()]);
+        let (tick, mut quiet) = (self.tick(), Instant::now());
+        let mut pool = self.pooled().then(|| Pool::new(self.workers.get()));
+        let (started, mut shutdown) = (Instant::now(), Box::pin(shutdown_signal()));
+        while remaining != Some(0)
+            && !self.broken.get()
+            && self
+                .duration
+                .is_none_or(|duration| started.elapsed() < duration)
+        {
+            let delivery = tokio::select! {
+                () = &mut shutdown => break,
+                delivery = tokio::time::timeout(tick, deliveries.recv()) => delivery,
+            };
+            if let Ok(delivery) = delivery {
+                (last, quiet) = (Instant::now(), Instant::now());
+                let (i, channel, delivery) = delivery.unwrap();
+                let queue = &queues[i];
+                let delivery = match delivery {
+                    Ok(delivery) => delivery,
+                    Err((id, err)) => {
+                        self.recover(conn, &mut consumers, &mut batch, queues, id, &err)
+                            .await;
+                        continue;
+                    }
+                };
+                if self.report_active && active[i] != Some(true) {
+                    eprintln!("active consumer of {queue}");
+                    active[i] = Some(true);
+                }
+                self.dispatch(&mut batch, pool.as_mut(), channel, delivery, queue)
+                    .await;
+                if let Some(remaining) = &mut remaining {
+                    *remaining -= 1;
+                }
+                self.report_progress(&consumers.channels[0], queues).await;
+            } else {
+                self.flush_due();
+                if quiet.elapsed() < IDLE_TICK {
+                    continue;
+                }
+                quiet = Instant::now();
+                self.report_progress(&consumers.channels[0], queues).await;
+                self.collect(&mut batch, pool.as_mut(), false).await;
+                batch.flush(&self.output).await;
+                if idle_timeout.is_some_and(|timeout| last.elapsed() >= timeout) {
+                    idle = true;
+                    break;
+                }
+                if self.drain || (self.report_active && active.contains(&None)) {
+                    let mut drained = true;
+                    for (queue, active) in queues.iter().zip(&mut active) {
+                        let ready = ready_messages(&consumers.channels[0], queue).await;
+                        if self.report_active && active.is_none() && ready > 0 {
+                            eprintln!("standby consumer of {queue}, another consumer is active");
+                            *active = Some(false);
+                        }
+                        drained &= ready == 0;
+                    }
+                    if self.drain && drained {
+                        break;
+                    }
+                }
+            }
+        }
+        for sub in &consumers.subscriptions {
+            consumers.channels[sub.channel]
+                .basic_cancel(sub.tag.as_str(), BasicCancelOptions::default())
+                .await
+                .unwrap();
+        }
+        self.collect(&mut batch, pool.as_mut(), true).await;
+        batch.flush(&self.output).await;
+        self.report(pool.as_ref());
+        for chan in consumers.channels {
+            chan.close(REPLY_SUCCESS, "OK").await.unwrap();
+        }
+        if let Some(conn) = consumers.conn {
+            conn.close(REPLY_SUCCESS, "OK").await.unwrap();
+        }
+        if idle {
+            IDLE_EXIT_CODE
+        } else if self.broken.get() {
+            WRITE_ERROR_EXIT_CODE
+        } else {
+            0
+        }
+    }
+
+    /// Prints the statistics asked for once consumption has stopped.
+    fn report(&self, pool: Option<&Pool>) {
+        if let Some(pool) = pool {
+            pool.report();
+        }
+        if self.stats || self.stats_file.is_some() {
+            self.totals.report(self.stats_file.as_deref());
+        }
+        if self.latency {
+            self.latencies.report();
+        }
+    }
+
+    /// Opens the output and sets up everything needed to process and republish messages.
+    async fn open(&mut self, conn: &Connection, chan: &Channel, queues: &[String]) {
+        self.output.open();
+        // Handlers may exit without reading all of their input, failing the message rather than the process.
+        if self.pipe_safe || self.exec.is_some() {
+            ignore_signal_pipe();
+        }
+        if self.progress {
+            self.meter = Some(Progress::default());
+        }
+        if let Some(max_retries) = self.max_retries {
+            for queue in queues {
+                self.declare_retry_queues(chan, queue, max_retries).await;
+            }
+        }
+        if self.error_exchange.is_some() || self.max_retries.is_some() {
+            let errors = conn.create_channel().await.unwrap();
+            errors
+                .confirm_select(ConfirmSelectOptions::default())
+                .await
+                .unwrap();
+            self.errors = Some(errors);
+        }
+    }
+
+    /// Opens the channels to consume on and starts consuming from the queue of each of them.
+    async fn start(
+        &self,
+        addr: &str,
+        conn: &Connection,
+        chan: Channel,
+        queues: &[String],
+    ) -> (Consumers, Deliveries) {
+        let mut channels = vec![chan];
+        for _ in 1..self.concurrency.get() * queues.len() {
+            channels.push(conn.create_channel().await.unwrap());
+        }
+        let (mut consumers, deliveries) = Consumers::new(channels, self.channel_retries, addr);
+        for channel in 0..consumers.channels.len() {
+            self.consume_on(&mut consumers, channel, queues)
+                .await
+                .unwrap();
+        }
+        (consumers, deliveries)
+    }
+
+    /// Consumes from the queue of the channel, forwarding the deliveries tagged with their queue and channel.
+    async fn consume_on(
+        &self,
+        consumers: &mut Consumers,
+        channel: usize,
+        queues: &[String],
+    ) -> lapin::Result<()> {
+        self.consume_queue(consumers, channel, channel % queues.len(), queues)
+            .await
+    }
+
+    /// Consumes from the queue on the channel, forwarding its deliveries and the error ending them, if any.
+    async fn consume_queue(
+        &self,
+        consumers: &mut Consumers,
+        channel: usize,
+        i: usize,
+        queues: &[String],
+    ) -> lapin::Result<()> {
+        let tag = match queues.len() {
+            1 => Cow::Borrowed(self.consumer_tag.as_str()),
+            _ if self.consumer_tag.is_empty() => Cow::Borrowed(""),
+            _ => Cow::Owned(format!("{}-{i}", self.consumer_tag)),
+        };
+        let chan = &consumers.channels[channel];
+        let mut consumer = self.consume(chan, &queues[i], &tag).await?;
+        let id = consumers.next_id;
+        consumers.next_id += 1;
+        consumers.subscriptions.push(Subscription {
+            channel,
+            queue: i,
+            id,
+            tag: consumer.tag(),
+        });
+        let sender = consumers.sender.clone();
+        tokio::spawn(async move {
+            while let Some(delivery) = consumer.next().await {
+                if sender
+                    .send((i, channel, delivery.map_err(|err| (id, err))))
+                    .is_err()
+                {
+                    return;
+                }
+            }
+            let closed = lapin::Error::InvalidChannelState(ChannelState::Closed);
+            sender.send((i, channel, Err((id, closed)))).ok();
+        });
+        Ok(())
+    }
+
+    /// Consumes again after a consumer failed or was cancelled, reopening its channel and the connection if they
+    /// closed, unless it has already been replaced.
+    async fn recover(
+        &mut self,
+        conn: &Connection,
+        consumers: &mut Consumers,
+        batch: &mut Batch,
+        queues: &[String],
+        id: u64,
+        err: &lapin::Error,
+    ) {
+        let Some(position) = consumers.subscriptions.iter().position(|sub| sub.id == id) else {
+            return;
+        };
+        let Subscription { channel, queue, .. } = consumers.subscriptions.remove(position);
+        let mut reopen = !consumers.channels[channel].status().connected();
+        if reopen {
+            eprintln!("channel error: {err}, reopening channel");
+        } else {
+            eprintln!(
+                "consumer of {} was cancelled, consuming again",
+                queues[queue]
+            );
+        }
+        let mut err = err.to_string();
+        loop {
+            assert!(consumers.retries > 0, "cannot resume consuming: {err}");
+            consumers.retries -= 1;
+            if reopen {
+                batch.discard(channel);
+                consumers.subscriptions.retain(|sub| sub.channel != channel);
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            let resumed = if reopen {
+                self.reopen(conn, consumers, channel, queues).await
+            } else {
+                self.consume_queue(consumers, channel, queue, queues).await
+            };
+            match resumed {
+                Ok(()) => return,
+                Err(failed) => {
+                    eprintln!("cannot resume consuming: {failed}");
+                    (err, reopen) = (failed.to_string(), true);
+                }
+            }
+        }
+    }
+
+    /// Reopens the channel and consumes from the queues on it again, reconnecting first if the connection closed.
+    async fn reopen(
+        &mut self,
+        conn: &Connection,
+        consumers: &mut Consumers,
+        channel: usize,
+        queues: &[String],
+    ) -> lapin::Result<()> {
+        if !consumers.connection(conn).status().connected() {
+            let reconnected =
+                Connection::connect(&consumers.addr, ConnectionProperties::default()).await?;
+            eprintln!("reconnected to {}", consumers.addr);
+            if self.errors.is_some() {
+                let errors = reconnected.create_channel().await?;
+                errors
+                    .confirm_select(ConfirmSelectOptions::default())
+                    .await?;
+                self.errors = Some(errors);
+            }
+            consumers.conn = Some(reconnected);
+        }
+        let chan = consumers.connection(conn).create_channel().await?;
+        consumers.channels[channel] = chan;
+        self.consume_on(consumers, channel, queues).await
+    }
+
+    /// The prefetch of each consumer, no more than the number of messages to process.
+    fn prefetch(&self) -> u16 {
+        let prefetch = self.prefetch.unwrap_or_else(|| {
+            if self.pooled() {
+                (self.workers.get() * WORKER_PREFETCH)
+                    .try_into()
+                    .unwrap_or(u16::MAX)
+            } else {
+                DEFAULT_PREFETCH
+            }
+        });
+        self.count.map_or(prefetch, |count| {
+            prefetch.min(count.try_into().unwrap_or(u16::MAX))
+        })
+    }
+
+    /// Number of messages to acknowledge at once, no more than the prefetch so that the broker never stops sending
+    /// messages while they wait to be acknowledged.
+    fn ack_limit(&self) -> u16 {
+        match self.prefetch() {
+            0 => self.ack_batch,
+            prefetch => self.ack_batch.min(prefetch),
+        }
+    }
+
+    /// Sets the prefetch and starts consuming from the queue.
+    async fn consume(&self, chan: &Channel, queue: &str, tag: &str) -> lapin::Result<Consumer> {
+        chan.basic_qos(self.prefetch(), BasicQosOptions::default())
+            .await?;
+        let mut args = field_table(self.consume_args.clone());
+        if let Some(priority) = self.consumer_priority {
+            args.insert("x-priority".into(), AMQPValue::LongInt(priority));
+        }
+        chan.basic_consume(
+            queue,
+            tag,
+            BasicConsumeOptions {
+                no_ack: self.no_ack,
+                exclusive: self.exclusive,
+                ..BasicConsumeOptions::default()
+            },
+            args,
+        )
+        .await
+    }
+
+    /// Settles a processed message according to the policy, or republishes it to the error exchange if it failed.
+    async fn settle(
+        &self,
+        batch: &mut Batch,
+        channel: usize,
+        delivery: Delivery,
+        queue: &str,
+        outcome: Outcome,
+    ) {
+        let policy = match (outcome, &self.errors) {
+            (Ok(policy), _) => policy,
+            (Err((_, err)), Some(errors)) => {
+                eprintln!("{err}");
+                self.totals.failed();
+                self.republish(errors, &delivery, queue, &err).await;
+                Policy::Ack
+            }
+            (Err((policy, err)), None) => {
+                eprintln!("{err}");
+                self.totals.failed();
+                policy
+            }
+        };
+        if self.no_ack {
+            batch.push(None, &self.output).await;
+            return;
+        }
+        let acker = delivery.acker;
+        match policy {
+            Policy::Ack if self.ack_mode == AckMode::Individual || self.pooled() => {
+                if !self.pipe_safe {
+                    self.output.flush().unwrap();
+                }
+                settled(acker.ack(BasicAckOptions::default()).await);
+                self.totals.acked();
+            }
+            Policy::Ack => {
+                batch.push(Some((channel, acker)), &self.output).await;
+                self.totals.acked();
+            }
+            Policy::Reject | Policy::DeadLetter => {
+                self.totals.rejected();
+                let requeue = policy == Policy::Reject && self.requeue_on_error;
+                settled(acker.reject(BasicRejectOptions { requeue }).await);
+                batch.push(None, &self.output).await;
+            }
+            Policy::NackRequeue => {
+                self.totals.requeued();
+                let nacked = acker
+                    .nack(BasicNackOptions {
+                        multiple: false,
+                        requeue: true,
+                    })
+                    .await;
+                settled(nacked);
+                batch.push(None, &self.output).await;
+            }
+        }
+    }
+
+    /// Records and prints the latency of the message, if it says when it was published.
+    fn measure(&self, delivery: &Delivery, queue: &str) {
+        let published = match &self.latency_header {
+            Some(name) => header(delivery, name).and_then(|published| published.parse().ok()),
+            None => delivery
+                .properties
+                .timestamp()
+                .map(|published| published.saturating_mul(1000)),
+        };
+        if let Some(published) = published {
+            let latency = self.latencies.record(published);
+            eprintln!("{queue} {}: {latency}ms", delivery.delivery_tag);
+        }
+    }
+
+    /// Reports progress if it is due, with the number of messages ready in the queues as the backlog.
+    async fn report_progress(&self, chan: &Channel, queues: &[String]) {
+        let Some(meter) = self.meter.as_ref().filter(|meter| meter.due()) else {
+            return;
+        };
+        let mut backlog = 0;
+        for queue in queues {
+            backlog += u64::from(ready_messages(chan, queue).await);
+        }
+        meter.report(Some(backlog));
+    }
+
+    /// Whether commands are run by a pool of workers.
+    fn pooled(&self) -> bool {
+        self.exec.is_some() && self.workers.get() > 1
+    }
+
+    /// Handles the message, or hands it to a free worker when there is a pool of them.
+    async fn dispatch(
+        &self,
+        batch: &mut Batch,
+        pool: Option<&mut Pool>,
+        channel: usize,
+        delivery: Delivery,
+        queue: &str,
+    ) {
+        self.totals.received(delivery.data.len());
+        if let Some(meter) = &self.meter {
+            meter.record(delivery.data.len());
+        }
+        if self.latency {
+            self.measure(&delivery, queue);
+        }
+        let (Some(pool), Some(cmd)) = (pool, &self.exec) else {
+            let outcome = self.handle(&delivery, queue).await;
+            return self.settle(batch, channel, delivery, queue, outcome).await;
+        };
+        let body = self.output.decompress(&delivery);
+        if let Some(policy) = self.screen(&delivery, body.as_deref().unwrap_or(&delivery.data)) {
+            return self
+                .settle(batch, channel, delivery, queue, Ok(policy))
+                .await;
+        }
+        let decompressed = match body {
+            Ok(Cow::Owned(body)) => Some(body),
+            Ok(Cow::Borrowed(_)) => None,
+            Err(err) => {
+                let outcome = Err((self.on_invalid_utf8, err.to_string()));
+                return self.settle(batch, channel, delivery, queue, outcome).await;
+            }
+        };
+        while let Some(done) = pool.try_next() {
+            self.finish(batch, done).await;
+        }
+        if pool.is_full() {
+            let done = pool.next().await.unwrap();
+            self.finish(batch, done).await;
+        }
+        pool.spawn(cmd, queue, channel, delivery, decompressed);
+    }
+
+    /// Settles the messages whose commands have finished, optionally waiting for those still running.
+    async fn collect(&self, batch: &mut Batch, pool: Option<&mut Pool>, wait: bool) {
+        let Some(pool) = pool else {
+            return;
+        };
+        loop {
+            let done = if wait {
+                pool.next().await
+            } else {
+                pool.try_next()
+            };
+            let Some(done) = done else {
+                break;
+            };
+            self.finish(batch, done).await;
+        }
+    }
+
+    /// Settles a message according to the result of its command.
+    async fn finish(&self, batch: &mut Batch, done: Done) {
+        let outcome = self.executed(done.result);
+        self.settle(batch, done.channel, done.delivery, &done.queue, outcome)
+            .await;
+    }
+
+    /// Maps the result of a command to how the message should be settled.
+    fn executed(&self, result: Result<(), String>) -> Outcome {
+        result
+            .map(|()| Policy::Ack)
+            .map_err(|err| (self.on_exec_error, err))
+    }
+
+    /// Gives the policy for messages which are filtered out or not sampled, or none for those to handle, matching the
+    /// decompressed body.
+    fn screen(&self, delivery: &Delivery, body: &[u8]) -> Option<Policy> {
+        if !self.filter.matches(delivery, body) {
+            return Some(self.on_mismatch);
+        }
+        if !self.filter.sampled() {
+            return Some(Policy::Ack);
+        }
+        None
+    }
+
+    /// Writes out or executes the message unless it is filtered out, returning how it should be settled.
+    async fn handle(&self, delivery: &Delivery, queue: &str) -> Outcome {
+        // Bodies which cannot be decompressed are matched as they are, failing once they are written out.
+        let body = self.output.decompress(delivery);
+        if let Some(policy) = self.screen(delivery, body.as_deref().unwrap_or(&delivery.data)) {
+            return Ok(policy);
+        }
+        if let Some(cmd) = &self.exec {
+            return match body {
+                Ok(body) => self.executed(exec(cmd, delivery, &body, queue).await),
+                Err(err) => Err((self.on_invalid_utf8, err.to_string())),
+            };
+        }
+        let written = body.and_then(|body| self.output.write_decompressed(delivery, queue, body));
+        let written = written.and_then(|()| {
+            if self.pipe_safe {
+                self.output.flush()
+            } else {
+                Ok(())
+            }
+        });
+        match written {
+            Ok(()) => Ok(Policy::Ack),
+            Err(err @ Error::Write(_)) if self.pipe_safe => {
+                eprintln!("{err}");
+                self.totals.failed();
+                self.broken.set(true);
+                Ok(Policy::NackRequeue)
+            }
+            Err(err @ Error::Write(_)) => panic!("{err}"),
+            Err(err @ Error::Delimiter(_)) => Err((self.on_newline, err.to_string())),
+            Err(err) => Err((self.on_invalid_utf8, err.to_string())),
+        }
+    }
+
+    /// Republishes a failed message to the error exchange along with why it failed.
+    async fn republish(&self, errors: &Channel, delivery: &Delivery, queue: &str, err: &str) {
+        let mut headers = delivery.properties.headers().clone().unwrap_or_default();
+        let (exchange, routing_key) = if let Some(max_retries) = self.max_retries {
+            let retries =
+                header(delivery, RETRY_COUNT).map_or(0, |count| count.parse().unwrap_or(0));
+            headers.insert(RETRY_COUNT.into(), AMQPValue::LongUInt(retries + 1));
+            let target = if retries < max_retries {
+                format!("{queue}.retry.{}", retries + 1)
+            } else {
+                format!("{queue}.dlq")
+            };
+            ("", Cow::Owned(target))
+        } else {
+            let routing_key = self
+                .error_routing_key
+                .as_deref()
+                .unwrap_or(delivery.routing_key.as_str());
+            let exchange = self.error_exchange.as_deref().unwrap_or_default();
+            (exchange, Cow::Borrowed(routing_key))
+        };
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        for (key, value) in [
+            ("x-error", AMQPValue::LongString(err.into())),
+            (
+                "x-error-timestamp",
+                AMQPValue::Timestamp(timestamp.as_secs()),
+            ),
+            (
+                "x-original-exchange",
+                AMQPValue::LongString(delivery.exchange.as_str().into()),
+            ),
+            (
+                "x-original-routing-key",
+                AMQPValue::LongString(delivery.routing_key.as_str().into()),
+            ),
+            ("x-original-queue", AMQPValue::LongString(queue.into())),
+        ] {
+            headers.insert(key.into(), value);
+        }
+        let properties = delivery.properties.clone().with_headers(headers);
+        let confirm = errors
+            .basic_publish(
+                exchange,
+                &routing_key,
+                BasicPublishOptions::default(),
+                &delivery.data,
+                properties,
+            )
+            .await
+            .unwrap()
+            .await
+            .unwrap();
+        assert!(
+            confirm.is_ack(),
+            "broker did not confirm the message republished to {routing_key}"
+        );
+    }
+
+    /// Declares the delay queues for each retry, which dead letter back into the queue, and the dead letter queue.
+    async fn declare_retry_queues(&self, chan: &Channel, queue: &str, max_retries: u32) {
+        let options = QueueDeclareOptions {
+            durable: true,
+            ..QueueDeclareOptions::default()
+        };
+        for retry in 1..=max_retries {
+            let ttl = self.retry_delay_ms.saturating_mul(1 << (retry - 1).min(31));
+            let mut args = FieldTable::default();
+            args.insert("x-message-ttl".into(), AMQPValue::LongUInt(ttl));
+            args.insert(
+                "x-dead-letter-exchange".into(),
+                AMQPValue::LongString("".into()),
+            );
+            args.insert(
+                "x-dead-letter-routing-key".into(),
+                AMQPValue::LongString(queue.into()),
+            );
+            chan.queue_declare(&format!("{queue}.retry.{retry}"), options, args)
+                .await
+                .unwrap();
+        }
+        chan.queue_declare(&format!("{queue}.dlq"), options, FieldTable::default())
+            .await
+            .unwrap();
+    }
+}
+
+/// Acknowledges processed messages several at a time.
+struct Batch {
+    /// Acknowledges the latest message on each channel along with all those before it.
+    ackers: Vec<Option<Acker>>,
+
+    /// Number of messages processed since the last acknowledgement.
+    size: u16,
+
+    /// Number of messages after which to acknowledge.
+    limit: u16,
+
+    /// Whether each message was flushed as it was written, so stdout need not be flushed before acknowledging.
+    flushed: bool,
+}
+
+impl Batch {
+    /// Creates an empty batch for the given number of channels.
+    fn new(limit: u16, channels: usize, flushed: bool) -> Self {
+        Self {
+            ackers: (0..channels).map(|_| None).collect(),
+            size: 0,
+            limit,
+            flushed,
+        }
+    }
+
+    /// Counts a processed message, acknowledging the batch once it is full.
+    async fn push(&mut self, acker: Option<(usize, Acker)>, output: &OutputOpts) {
+        if let Some((channel, acker)) = acker {
+            self.ackers[channel] = Some(acker);
+        }
+        self.size += 1;
+        if self.size >= self.limit {
+            self.flush(output).await;
+        }
+    }
+
+    /// Forgets the messages processed from a channel which has closed, the broker redelivers them.
+    fn discard(&mut self, channel: usize) {
+        self.ackers[channel] = None;
+    }
+
+    /// Flushes the output and acknowledges every message processed so far.
+    async fn flush(&mut self, output: &OutputOpts) {
+        if !self.flushed {
+            output.flush().unwrap();
+        }
+        for acker in &mut self.ackers {
+            if let Some(acker) = acker.take() {
+                settled(acker.ack(BasicAckOptions { multiple: true }).await);
+            }
+        }
+        self.size = 0;
+    }
+}
+
+/// The channels being consumed on.
+struct Consumers {
+    /// The open channels.
+    channels: Vec<Channel>,
+
+    /// The consumer of each queue on each channel.
+    subscriptions: Vec<Subscription>,
+
+    /// Id of the next consumer.
+    next_id: u64,
+
+    /// Where the consumers forward their deliveries.
+    sender: mpsc::UnboundedSender<Forwarded>,
+
+    /// Number of times consumers may still be reestablished.
+    retries: u32,
+
+    /// Broker address to reconnect to.
+    addr: String,
+
+    /// The connection replacing the original one once it closed.
+    conn: Option<Connection>,
+}
+
+/// A consumer of a queue on a channel.
+struct Subscription {
+    /// Index of the channel.
+    channel: usize,
+
+    /// Index of the queue.
+    queue: usize,
+
+    /// Tells this consumer apart from those it replaced, which may have the same tag.
+    id: u64,
+
+    /// The consumer tag the broker knows it by.
+    tag: ShortString,
+}
+
+impl Consumers {
+    /// Wraps the channels, returning where the deliveries of their consumers arrive.
+    fn new(channels: Vec<Channel>, retries: u32, addr: &str) -> (Self, Deliveries) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let consumers = Self {
+            channels,
+            subscriptions: vec![],
+            next_id: 0,
+            sender,
+            retries,
+            addr: addr.into(),
+            conn: None,
+        };
+        (consumers, receiver)
+    }
+
+    /// The connection channels are opened on, the original one unless it had to be replaced.
+    fn connection<'a>(&'a self, original: &'a Connection) -> &'a Connection {
+        self.conn.as_ref().unwrap_or(original)
+    }
+}
+
+/// Reports a failure to settle a message, whose channel has closed so the broker redelivers it.
+fn settled(result: lapin::Result<()>) {
+    if let Err(err) = result {
+        eprintln!("cannot settle message: {err}");
+    }
+}
+
+/// Waits for an interrupt, or a terminate signal on unix.
+async fn shutdown_signal() {
+    #[cfg(target_family = "unix")]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).unwrap();
+        tokio::select! {
+            interrupt = tokio::signal::ctrl_c() => interrupt.unwrap(),
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(target_family = "unix"))]
+    tokio::signal::ctrl_c().await.unwrap();
+}
+
+/// Lets writes to a closed pipe fail instead of killing the process, so they can be handled.
+fn ignore_signal_pipe() {
+    #[cfg(target_family = "unix")]
+    {
+        use nix::sys::signal;
+        unsafe { signal::signal(signal::Signal::SIGPIPE, signal::SigHandler::SigIgn) }.unwrap();
+    }
+}
+
+/// Counts the messages in the queue which are ready for delivery.
+async fn ready_messages(chan: &Channel, queue: &str) -> u32 {
+    chan.queue_declare(
+        queue,
+        QueueDeclareOptions {
+            passive: true,
+            ..QueueDeclareOptions::default()
+        },
+        FieldTable::default(),
+    )
+    .await
+    .unwrap()
+    .message_count()
+}
@@ -0,0 +1,142 @@
+//! Decoding binary message bodies into json.
+use apache_avro::{reader::datum::GenericDatumReader, Reader, Schema};
+use prost_reflect::{DescriptorPool, DynamicMessage, MessageDescriptor};
+use std::{fs, path::PathBuf, str::FromStr};
+use structopt::StructOpt;
+
+/// Options for rendering binary encoded bodies as json text.
+#[derive(StructOpt)]
+pub struct DecodeOpts {
+    /// Protobuf file descriptor set, as produced by `protoc --descriptor_set_out`, to decode bodies with.
+    #[structopt(long, requires = "proto-type")]
+    proto_descriptor: Option<PathBuf>,
+
+    /// Fully qualified name of the protobuf message type of the bodies, e.g. `my.pkg.Event`.
+    #[structopt(long, requires = "proto-descriptor")]
+    proto_type: Option<String>,
+
+    /// Avro schema to decode bodies with, which may also be in the confluent wire format or avro container files.
+    #[structopt(long, conflicts_with = "proto-descriptor")]
+    avro_schema: Option<PathBuf>,
+
+    /// Either msgpack or cbor, to convert bodies in that encoding to json.
+    #[structopt(long, possible_values = &["msgpack", "cbor"], conflicts_with_all = &["proto-descriptor", "avro-schema"])]
+    decode: Option<Codec>,
+
+    /// The protobuf message type once loaded.
+    #[structopt(skip)]
+    proto: Option<MessageDescriptor>,
+
+    /// The avro schema once loaded.
+    #[structopt(skip)]
+    avro: Option<Schema>,
+}
+
+impl DecodeOpts {
+    /// Loads the schemas.
+    pub fn open(&mut self) {
+        if let (Some(path), Some(name)) = (&self.proto_descriptor, &self.proto_type) {
+            let pool = DescriptorPool::decode(fs::read(path).unwrap().as_slice()).unwrap();
+            let proto = pool.get_message_by_name(name);
+            self.proto = Some(proto.unwrap_or_else(|| panic!("unknown protobuf type: {name}")));
+        }
+        if let Some(path) = &self.avro_schema {
+            self.avro = Some(Schema::parse_str(&fs::read_to_string(path).unwrap()).unwrap());
+        }
+    }
+
+    /// Decodes the body into json text, giving none when no decoding is configured.
+    pub fn decode(&self, body: &[u8]) -> Option<Result<Vec<u8>, String>> {
+        if let Some(codec) = self.decode {
+            return Some(
+                codec
+                    .decode(body)
+                    .map(|value| value.to_string().into_bytes()),
+            );
+        }
+        if let Some(schema) = &self.avro {
+            return Some(avro(schema, body).map(|value| value.to_string().into_bytes()));
+        }
+        let proto = self.proto.as_ref()?;
+        Some(
+            DynamicMessage::decode(proto.clone(), body)
+                .map_err(|err| format!("cannot decode protobuf body: {err}"))
+                .and_then(|message| serde_json::to_vec(&message).map_err(|err| err.to_string())),
+        )
+    }
+}
+
+/// A self describing binary encoding which maps onto json.
+#[derive(Clone, Copy)]
+pub enum Codec {
+    /// `MessagePack`.
+    Msgpack,
+
+    /// Concise binary object representation.
+    Cbor,
+}
+
+impl FromStr for Codec {
+    type Err = String;
+
+    fn from_str(codec: &str) -> Result<Self, Self::Err> {
+        match codec {
+            "msgpack" => Ok(Self::Msgpack),
+            "cbor" => Ok(Self::Cbor),
+            _ => Err(format!("unknown codec: {codec}")),
+        }
+    }
+}
+
+impl Codec {
+    /// Decodes the body into json.
+    fn decode(self, body: &[u8]) -> Result<serde_json::Value, String> {
+        match self {
+            Self::Msgpack => rmp_serde::from_slice(body)
+                .map_err(|err| format!("cannot decode msgpack body: {err}")),
+            Self::Cbor => {
+                ciborium::from_reader(body).map_err(|err| format!("cannot decode cbor body: {err}"))
+            }
+        }
+    }
+
+    /// Encodes json text.
+    pub fn encode(self, json: &str) -> Result<Vec<u8>, String> {
+        let value: serde_json::Value = serde_json::from_str(json).map_err(|err| err.to_string())?;
+        match self {
+            Self::Msgpack => rmp_serde::to_vec(&value).map_err(|err| err.to_string()),
+            Self::Cbor => {
+                let mut body = vec![];
+                ciborium::into_writer(&value, &mut body).map_err(|err| err.to_string())?;
+                Ok(body)
+            }
+        }
+    }
+}
+
+/// Decodes an avro container file, or a single datum optionally in the confluent wire format.
+fn avro(schema: &Schema, body: &[u8]) -> Result<serde_json::Value, String> {
+    let error = |err: apache_avro::Error| format!("cannot decode avro body: {err}");
+    if body.starts_with(b"Obj\x01") {
+        let values = Reader::new(body)
+            .map_err(error)?
+            .map(|value| value.and_then(serde_json::Value::try_from).map_err(error))
+            .collect::<Result<Vec<_>, _>>()?;
+        return Ok(match <[_; 1]>::try_from(values) {
+            Ok([value]) => value,
+            Err(values) => values.into(),
+        });
+    }
+    let reader = GenericDatumReader::builder(schema).build().map_err(error)?;
+    let datum = |mut data: &[u8]| {
+        let value = reader.read_value(&mut data).map_err(error)?;
+        if !data.is_empty() {
+            return Err(format!("{} trailing bytes after avro datum", data.len()));
+        }
+        serde_json::Value::try_from(value).map_err(error)
+    };
+    match body {
+        [0, _, _, _, _, data @ ..] => datum(data).or_else(|_| datum(body)),
+        _ => datum(body),
+    }
+}
@@ -0,0 +1,170 @@
+//! Handing consumed messages to subprocesses.
+use core::time::Duration;
+use lapin::message::Delivery;
+use std::{process::Stdio, time::Instant};
+use tokio::{io::AsyncWriteExt, process::Command, task::JoinSet};
+
+/// Runs commands for several messages at once.
+pub struct Pool {
+    /// Commands which are running.
+    tasks: JoinSet<Done>,
+
+    /// Workers which are not running a command.
+    free: Vec<usize>,
+
+    /// Statistics per worker.
+    stats: Vec<Stats>,
+}
+
+/// A message for which the command has finished.
+pub struct Done {
+    /// The worker which ran the command.
+    worker: usize,
+
+    /// How long the command took.
+    elapsed: Duration,
+
+    /// The queue the message came from.
+    pub queue: String,
+
+    /// The channel the message came from.
+    pub channel: usize,
+
+    /// The message.
+    pub delivery: Delivery,
+
+    /// Whether the command succeeded.
+    pub result: Result<(), String>,
+}
+
+/// What a worker has done so far.
+#[derive(Clone, Copy, Default)]
+struct Stats {
+    /// Number of messages handled.
+    messages: u64,
+
+    /// Number of messages for which the command failed.
+    failures: u64,
+
+    /// Total time spent running commands.
+    total: Duration,
+
+    /// Longest time spent running a command.
+    max: Duration,
+}
+
+impl Pool {
+    /// Creates a pool with the given number of idle workers.
+    pub fn new(workers: usize) -> Self {
+        Self {
+            tasks: JoinSet::new(),
+            free: (0..workers).rev().collect(),
+            stats: vec![Stats::default(); workers],
+        }
+    }
+
+    /// Whether every worker is running a command.
+    pub fn is_full(&self) -> bool {
+        self.free.is_empty()
+    }
+
+    /// Runs the command for the message on a free worker, piping it the decompressed body if there is one.
+    pub fn spawn(
+        &mut self,
+        cmd: &str,
+        queue: &str,
+        channel: usize,
+        delivery: Delivery,
+        decompressed: Option<Vec<u8>>,
+    ) {
+        let worker = self.free.pop().expect("no free worker");
+        let (cmd, queue) = (cmd.to_owned(), queue.to_owned());
+        self.tasks.spawn(async move {
+            let start = Instant::now();
+            let body = decompressed.as_deref().unwrap_or(&delivery.data);
+            let result = exec(&cmd, &delivery, body, &queue).await;
+            Done {
+                worker,
+                elapsed: start.elapsed(),
+                queue,
+                channel,
+                delivery,
+                result,
+            }
+        });
+    }
+
+    /// Waits for a command to finish, giving none if none are running.
+    pub async fn next(&mut self) -> Option<Done> {
+        let done = self.tasks.join_next().await?.unwrap();
+        Some(self.record(done))
+    }
+
+    /// Takes a command which has already finished, if any.
+    pub fn try_next(&mut self) -> Option<Done> {
+        let done = self.tasks.try_join_next()?.unwrap();
+        Some(self.record(done))
+    }
+
+    /// Updates the statistics of the worker and frees it.
+    fn record(&mut self, done: Done) -> Done {
+        let stats = &mut self.stats[done.worker];
+        stats.messages += 1;
+        stats.failures += u64::from(done.result.is_err());
+        stats.total += done.elapsed;
+        stats.max = stats.max.max(done.elapsed);
+        self.free.push(done.worker);
+        done
+    }
+
+    /// Prints the statistics of each worker to stderr.
+    pub fn report(&self) {
+        for (worker, stats) in self.stats.iter().enumerate() {
+            let average = stats
+                .total
+                .checked_div(u32::try_from(stats.messages).unwrap_or(u32::MAX))
+                .unwrap_or_default();
+            eprintln!(
+                "worker {worker}: {} messages, {} failed, {average:?} average, {:?} max",
+                stats.messages, stats.failures, stats.max
+            );
+        }
+    }
+}
+
+/// Runs the shell command with the body on its stdin and the delivery attributes in its environment.
+pub async fn exec(cmd: &str, delivery: &Delivery, body: &[u8], queue: &str) -> Result<(), String> {
+    let mut child = shell(cmd)
+        .env("AMQP_QUEUE", queue)
+        .env("AMQP_EXCHANGE", delivery.exchange.as_str())
+        .env("AMQP_ROUTING_KEY", delivery.routing_key.as_str())
+        .env("AMQP_DELIVERY_TAG", delivery.delivery_tag.to_string())
+        .env("AMQP_REDELIVERED", delivery.redelivered.to_string())
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|err| format!("cannot run {cmd}: {err}"))?;
+    let mut stdin = child.stdin.take().unwrap();
+    let written = stdin.write_all(body).await;
+    drop(stdin);
+    let status = child.wait().await.map_err(|err| err.to_string())?;
+    if !status.success() {
+        return Err(format!("{cmd} failed with {status}"));
+    }
+    written.map_err(|err| format!("cannot write to {cmd}: {err}"))
+}
+
+/// Builds a command running the given command line through the shell.
+#[cfg(target_family = "unix")]
+fn shell(cmd: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(cmd);
+    shell
+}
+
+/// Builds a command running the given command line through the shell.
+#[cfg(not(target_family = "unix"))]
+fn shell(cmd: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(cmd);
+    shell
+}
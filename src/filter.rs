@@ -0,0 +1,249 @@
+//! Choosing which consumed messages to write out.
+use crate::{
+    output::{header, metadata},
+    rotate::parse_duration,
+    select::Selector,
+};
+use lapin::message::Delivery;
+use regex::bytes::Regex;
+use std::{
+    cell::Cell,
+    num::NonZeroU64,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use structopt::StructOpt;
+
+/// Options selecting which messages are written out, the rest are skipped.
+#[derive(StructOpt)]
+pub struct FilterOpts {
+    /// Only writes out messages whose body matches this regular expression.
+    #[structopt(long)]
+    grep: Option<Regex>,
+
+    /// Whether to write out only the messages whose body does not match the pattern instead.
+    #[structopt(short = "v", long, requires = "grep")]
+    invert_match: bool,
+
+    /// Only writes out messages with this header value, given as key=value, may be repeated.
+    #[structopt(long, number_of_values = 1, parse(try_from_str = parse_match))]
+    match_header: Vec<(String, String)>,
+
+    /// Only writes out messages whose routing key matches this glob, where * matches anything and ? any character.
+    #[structopt(long)]
+    rk_filter: Option<Glob>,
+
+    /// Only writes out messages satisfying an expression such as `headers.region = 'eu' AND properties.priority > 3`.
+    #[structopt(long)]
+    select: Option<Selector>,
+
+    /// Only writes out messages timestamped at or after this time, given as unix seconds, a utc date such as
+    /// 2024-01-31T12:00:00Z or a duration ago such as 1h, messages without a timestamp are skipped.
+    #[structopt(long, parse(try_from_str = parse_time))]
+    since: Option<u64>,
+
+    /// Only writes out messages timestamped at or before this time, given like --since.
+    #[structopt(long, parse(try_from_str = parse_time))]
+    until: Option<u64>,
+
+    /// Whether to skip messages which have been delivered before.
+    #[structopt(long, conflicts_with = "only-redelivered")]
+    skip_redelivered: bool,
+
+    /// Whether to skip messages which have not been delivered before.
+    #[structopt(long)]
+    only_redelivered: bool,
+
+    /// Fraction of messages to write out at random, e.g. 0.01, the rest are acknowledged.
+    #[structopt(long, parse(try_from_str = parse_fraction))]
+    sample: Option<f64>,
+
+    /// Writes out only every nth message, the rest are acknowledged.
+    #[structopt(long, conflicts_with = "sample")]
+    every: Option<NonZeroU64>,
+
+    /// Number of messages seen so far, for picking every nth.
+    #[structopt(skip)]
+    seen: Cell<u64>,
+}
+
+/// A shell style wildcard pattern matched against the whole text.
+pub struct Glob(regex::Regex);
+
+impl FromStr for Glob {
+    type Err = regex::Error;
+
+    fn from_str(glob: &str) -> Result<Self, Self::Err> {
+        let mut pattern = String::from("^");
+        for c in glob.chars() {
+            match c {
+                '*' => pattern.push_str(".*"),
+                '?' => pattern.push('.'),
+                c => pattern.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+            }
+        }
+        pattern.push('$');
+        pattern.parse().map(Self)
+    }
+}
+
+impl FilterOpts {
+    /// Whether the next message is among those sampled for writing out.
+    pub fn sampled(&self) -> bool {
+        let seen = self.seen.get();
+        self.seen.set(seen + 1);
+        self.every
+            .is_none_or(|every| seen.is_multiple_of(every.get()))
+            && self.sample.is_none_or(|sample| fastrand::f64() < sample)
+    }
+
+    /// Whether the message with the given decompressed body passes all the filters.
+    pub fn matches(&self, delivery: &Delivery, body: &[u8]) -> bool {
+        self.grep
+            .as_ref()
+            .is_none_or(|grep| grep.is_match(body) != self.invert_match)
+            && self
+                .match_header
+                .iter()
+                .all(|(key, value)| header(delivery, key).as_ref() == Some(value))
+            && self
+                .rk_filter
+                .as_ref()
+                .is_none_or(|glob| glob.matches(delivery.routing_key.as_str()))
+            && self
+                .select
+                .as_ref()
+                .is_none_or(|select| select.matches(&metadata(delivery)))
+            && self.within(*delivery.properties.timestamp())
+            && if delivery.redelivered {
+                !self.skip_redelivered
+            } else {
+                !self.only_redelivered
+            }
+    }
+
+    /// Whether the timestamp falls between --since and --until, if either is given.
+    fn within(&self, timestamp: Option<u64>) -> bool {
+        if self.since.is_none() && self.until.is_none() {
+            return true;
+        }
+        timestamp.is_some_and(|timestamp| {
+            self.since.is_none_or(|since| timestamp >= since)
+                && self.until.is_none_or(|until| timestamp <= until)
+        })
+    }
+}
+
+impl Glob {
+    /// Whether the whole text matches the pattern.
+    pub fn matches(&self, text: &str) -> bool {
+        self.0.is_match(text)
+    }
+}
+
+/// Parses a number between 0 and 1.
+fn parse_fraction(fraction: &str) -> Result<f64, String> {
+    match fraction.parse() {
+        Ok(fraction) if (0.0..=1.0).contains(&fraction) => Ok(fraction),
+        _ => Err(format!("expected a fraction between 0 and 1: {fraction}")),
+    }
+}
+
+/// Parses a point in time into unix seconds.
+fn parse_time(time: &str) -> Result<u64, String> {
+    if let Ok(seconds) = time.parse() {
+        return Ok(seconds);
+    }
+    if time.contains('-') {
+        return parse_date(time)
+            .ok_or_else(|| format!("expected a date such as 2024-01-31T12:00:00Z: {time}"));
+    }
+    let ago = parse_duration(time)?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    Ok(now.saturating_sub(ago).as_secs())
+}
+
+/// Parses a utc date with an optional time, e.g. 2024-01-31 or 2024-01-31T12:00:00Z, into unix seconds.
+fn parse_date(date: &str) -> Option<u64> {
+    let date = date.trim_end_matches('Z');
+    let (day, time) = date.split_once(['T', ' ']).unwrap_or((date, "00:00:00"));
+    let day: Vec<i64> = day
+        .split('-')
+        .map(str::parse)
+        .collect::<Result<_, _>>()
+        .ok()?;
+    let time: Vec<i64> = time
+        .split(':')
+        .map(str::parse)
+        .collect::<Result<_, _>>()
+        .ok()?;
+    let (&[year, month, day], &[hour, minute, second]) = (&day[..], &time[..]) else {
+        return None;
+    };
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days_in_month = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+    let valid_time =
+        (0..24).contains(&hour) && (0..60).contains(&minute) && (0..60).contains(&second);
+    if !(1..=12).contains(&month) || !(1..=days_in_month).contains(&day) || !valid_time {
+        return None;
+    }
+    // Counts days from the civil calendar, with years starting in march so leap days come last.
+    let year = if month <= 2 { year - 1 } else { year };
+    let (era, year_of_era) = (year.div_euclid(400), year.rem_euclid(400));
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+    (days * 86_400 + hour * 3600 + minute * 60 + second)
+        .try_into()
+        .ok()
+}
+
+/// Parses a header match of the form key=value.
+fn parse_match(header: &str) -> Result<(String, String), String> {
+    let (key, value) = header
+        .split_once('=')
+        .ok_or_else(|| format!("expected key=value: {header}"))?;
+    Ok((key.into(), value.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_date;
+
+    #[test]
+    fn parses_leap_days() {
+        assert_eq!(parse_date("2024-02-29"), Some(1_709_164_800));
+        assert_eq!(parse_date("2024-03-01"), Some(1_709_251_200));
+        assert_eq!(parse_date("2000-02-29T00:00:00Z"), Some(951_782_400));
+        assert_eq!(parse_date("2024-02-29T12:30:15Z"), Some(1_709_209_815));
+    }
+
+    #[test]
+    fn skips_leap_days_of_non_leap_years() {
+        assert_eq!(parse_date("2023-03-01"), Some(1_677_628_800));
+        assert_eq!(parse_date("2100-03-01 00:00:00"), Some(4_107_542_400));
+        assert_eq!(parse_date("1970-01-01"), Some(0));
+    }
+
+    #[test]
+    fn rejects_invalid_dates() {
+        assert_eq!(parse_date("2024-13-01"), None);
+        assert_eq!(parse_date("2024-01-32"), None);
+        assert_eq!(parse_date("2024-01"), None);
+        assert_eq!(parse_date("1969-12-31"), None);
+        assert_eq!(parse_date("2024-02-30"), None);
+        assert_eq!(parse_date("2023-02-29"), None);
+        assert_eq!(parse_date("2100-02-29"), None);
+        assert_eq!(parse_date("2024-04-31"), None);
+        assert_eq!(parse_date("2024-01-01T24:00:00Z"), None);
+        assert_eq!(parse_date("2024-01-01T99:99:99Z"), None);
+        assert_eq!(parse_date("2024-01-01T12:60:00Z"), None);
+        assert_eq!(parse_date("2024-01-01T12:00:60Z"), None);
+        assert_eq!(parse_date("2024-01-01T12:-1:00Z"), None);
+    }
+}
@@ -0,0 +1,94 @@
+//! Fetching messages one at a time without a consumer.
+use crate::output::OutputOpts;
+use lapin::{
+    acker::Acker,
+    options::{BasicAckOptions, BasicGetOptions, BasicNackOptions},
+    protocol::constants::REPLY_SUCCESS,
+    Channel,
+};
+use structopt::StructOpt;
+
+/// Options for fetching messages from a queue with basic.get.
+#[derive(StructOpt)]
+pub struct Get {
+    /// The queue from which to fetch.
+    queue: String,
+
+    /// Maximum number of messages to fetch.
+    #[structopt(short = "n", long, default_value = "1")]
+    count: u64,
+
+    /// Whether to acknowledge the messages, removing them from the queue.
+    #[structopt(long, conflicts_with = "requeue")]
+    ack: bool,
+
+    /// Whether to put the messages back on the queue, which is the default.
+    #[structopt(long)]
+    requeue: bool,
+
+    #[structopt(flatten)]
+    output: OutputOpts,
+}
+
+impl Get {
+    /// Fetches and writes out the messages, then acknowledges or requeues them all at once.
+    pub async fn run(self, chan: Channel) {
+        let requeue = self.requeue || !self.ack;
+        fetch(chan, &self.queue, self.count, self.output, requeue).await;
+    }
+}
+
+/// Options for inspecting a queue without removing anything from it.
+#[derive(StructOpt)]
+pub struct Peek {
+    /// The queue to inspect.
+    queue: String,
+
+    /// Maximum number of messages to print.
+    #[structopt(short = "n", long, default_value = "1")]
+    count: u64,
+
+    #[structopt(flatten)]
+    output: OutputOpts,
+}
+
+impl Peek {
+    /// Fetches and writes out the messages, then puts them all back on the queue.
+    pub async fn run(self, chan: Channel) {
+        fetch(chan, &self.queue, self.count, self.output, true).await;
+    }
+}
+
+/// Fetches up to count messages, holding them unacknowledged so none is fetched twice.
+async fn fetch(chan: Channel, queue: &str, count: u64, mut output: OutputOpts, requeue: bool) {
+    output.open();
+    let mut last: Option<Acker> = None;
+    for _ in 0..count {
+        let Some(message) = chan
+            .basic_get(queue, BasicGetOptions::default())
+            .await
+            .unwrap()
+        else {
+            break;
+        };
+        if let Err(err) = output.write(&message.delivery, queue) {
+            eprintln!("{err}");
+        }
+        last = Some(message.delivery.acker);
+    }
+    output.flush().unwrap();
+    if let Some(acker) = last {
+        if requeue {
+            acker
+                .nack(BasicNackOptions {
+                    multiple: true,
+                    requeue: true,
+                })
+                .await
+                .unwrap();
+        } else {
+            acker.ack(BasicAckOptions { multiple: true }).await.unwrap();
+        }
+    }
+    chan.close(REPLY_SUCCESS, "OK").await.unwrap();
+}
@@ -0,0 +1,67 @@
+//! Generating unique message ids.
+use std::{
+    fmt::Write,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// How message ids are generated.
+#[derive(Clone, Copy)]
+pub enum MessageId {
+    /// A random version 4 uuid.
+    Uuid,
+
+    /// A lexicographically sortable id made of the time in milliseconds and random bits.
+    Ulid,
+
+    /// The position of the message in the input, starting from 1.
+    Seq,
+}
+
+impl FromStr for MessageId {
+    type Err = String;
+
+    fn from_str(kind: &str) -> Result<Self, Self::Err> {
+        match kind {
+            "uuid" => Ok(Self::Uuid),
+            "ulid" => Ok(Self::Ulid),
+            "seq" => Ok(Self::Seq),
+            _ => Err(format!("unknown message id: {kind}")),
+        }
+    }
+}
+
+/// Digits of the crockford base32 encoding used by ulids.
+const CROCKFORD: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+impl MessageId {
+    /// Generates the id of the message at the given position.
+    pub fn generate(self, seq: u64) -> String {
+        match self {
+            Self::Uuid => {
+                let mut bytes = fastrand::u128(..).to_be_bytes();
+                bytes[6] = (bytes[6] & 0x0f) | 0x40;
+                bytes[8] = (bytes[8] & 0x3f) | 0x80;
+                let mut id = String::with_capacity(36);
+                for (i, byte) in bytes.iter().enumerate() {
+                    if matches!(i, 4 | 6 | 8 | 10) {
+                        id.push('-');
+                    }
+                    write!(id, "{byte:02x}").unwrap();
+                }
+                id
+            }
+            Self::Ulid => {
+                let millis = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_millis();
+                let ulid = (millis << 80) | (fastrand::u128(..) >> 48);
+                (0..26)
+                    .map(|i| char::from(CROCKFORD[(ulid >> (125 - 5 * i)) as usize & 31]))
+                    .collect()
+            }
+            Self::Seq => seq.to_string(),
+        }
+    }
+}
@@ -0,0 +1,267 @@
+//! Parsing the messages to publish from lines of input.
+use amq_protocol_types::{AMQPValue, FieldArray, FieldTable};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use lapin::BasicProperties;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::{num::NonZeroUsize, str::FromStr};
+
+/// How each line of input describes a message.
+#[derive(Clone, Copy)]
+pub enum Input {
+    /// The line is the body.
+    Text,
+
+    /// The line is a json object with the body and optionally its routing and properties.
+    Json,
+
+    /// The line is a row of comma separated values, which may be quoted.
+    Csv,
+}
+
+impl FromStr for Input {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            "csv" => Ok(Self::Csv),
+            _ => Err(format!("unknown input format: {input}")),
+        }
+    }
+}
+
+/// A message to publish, where anything it specifies overrides the options.
+pub struct Message {
+    /// Exchange to publish to instead of the one given.
+    pub exchange: Option<String>,
+
+    /// Routing key to publish with instead of the one given.
+    pub routing_key: Option<String>,
+
+    /// The properties, including the headers.
+    pub properties: BasicProperties,
+
+    /// The message body.
+    pub body: Vec<u8>,
+}
+
+/// Where the routing key and body are found in each line.
+#[derive(Clone, Copy)]
+pub struct Layout<'a> {
+    /// The column of csv rows holding the routing key, if any, numbered from 1.
+    pub rk_column: Option<NonZeroUsize>,
+
+    /// The column of csv rows holding the body, otherwise the whole row is the body.
+    pub body_column: Option<NonZeroUsize>,
+
+    /// What separates the routing key from the body in text lines, if they start with one.
+    pub rk_separator: Option<&'a str>,
+}
+
+/// A message as written by consume with `--output json`.
+#[derive(Deserialize)]
+struct Envelope {
+    /// Exchange to publish to.
+    exchange: Option<String>,
+
+    /// Routing key to publish with.
+    routing_key: Option<String>,
+
+    /// Properties by their names in the json output, e.g. `content_type`.
+    #[serde(default)]
+    properties: Map<String, Value>,
+
+    /// Headers, which are added to those given as options.
+    #[serde(default)]
+    headers: Map<String, Value>,
+
+    /// The body, as text or any other json value to publish serialized.
+    #[serde(default)]
+    body: Value,
+
+    /// Either absent or base64, for binary bodies.
+    body_encoding: Option<String>,
+}
+
+impl Input {
+    /// Parses a line into a message with the given properties unless the line overrides them.
+    pub fn parse(
+        self,
+        line: Vec<u8>,
+        properties: &BasicProperties,
+        layout: Layout,
+    ) -> Result<Message, String> {
+        match self {
+            Self::Text => {
+                let (routing_key, body) = match layout.rk_separator {
+                    Some(separator) => {
+                        let line = String::from_utf8(line).map_err(|err| err.to_string())?;
+                        let (routing_key, body) = line
+                            .split_once(separator)
+                            .ok_or_else(|| format!("no routing key separator in line: {line}"))?;
+                        (Some(routing_key.into()), body.as_bytes().to_vec())
+                    }
+                    None => (None, line),
+                };
+                Ok(Message {
+                    exchange: None,
+                    routing_key,
+                    properties: properties.clone(),
+                    body,
+                })
+            }
+            Self::Json => {
+                let envelope: Envelope =
+                    serde_json::from_slice(&line).map_err(|err| err.to_string())?;
+                envelope.message(properties.clone())
+            }
+            Self::Csv => {
+                let line = String::from_utf8(line).map_err(|err| err.to_string())?;
+                let fields = split_csv(&line);
+                let column = |column: NonZeroUsize| {
+                    fields
+                        .get(column.get() - 1)
+                        .cloned()
+                        .ok_or_else(|| format!("row has no column {column}: {line}"))
+                };
+                let routing_key = layout.rk_column.map(column).transpose()?;
+                let body = layout.body_column.map(column).transpose()?;
+                Ok(Message {
+                    exchange: None,
+                    routing_key,
+                    properties: properties.clone(),
+                    body: body.unwrap_or(line).into_bytes(),
+                })
+            }
+        }
+    }
+}
+
+/// Splits a csv row into its fields, unquoting those in double quotes where doubled quotes stand for one.
+fn split_csv(row: &str) -> Vec<String> {
+    let (mut fields, mut field, mut quoted) = (vec![], String::new(), false);
+    let mut chars = row.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+impl Envelope {
+    /// Converts the envelope into a message, overriding the given properties.
+    fn message(self, mut properties: BasicProperties) -> Result<Message, String> {
+        for (name, value) in self.properties {
+            properties = property(properties, &name, &value)?;
+        }
+        if !self.headers.is_empty() {
+            let mut headers = properties.headers().clone().unwrap_or_default();
+            for (key, value) in self.headers {
+                headers.insert(key.into(), amqp_value(value));
+            }
+            properties = properties.with_headers(headers);
+        }
+        let body = match (self.body, self.body_encoding.as_deref()) {
+            (Value::String(body), Some("base64")) => {
+                STANDARD.decode(body).map_err(|err| err.to_string())?
+            }
+            (_, Some(encoding)) => return Err(format!("unknown body encoding: {encoding}")),
+            (Value::String(body), None) => body.into_bytes(),
+            (Value::Null, None) => vec![],
+            (body, None) => body.to_string().into_bytes(),
+        };
+        Ok(Message {
+            exchange: self.exchange,
+            routing_key: self.routing_key,
+            properties,
+            body,
+        })
+    }
+}
+
+/// Sets the named property, as named in the json output.
+fn property(
+    properties: BasicProperties,
+    name: &str,
+    value: &Value,
+) -> Result<BasicProperties, String> {
+    if value.is_null() {
+        return Ok(properties);
+    }
+    let invalid = || format!("invalid {name} property: {value}");
+    let number = || value.as_u64().ok_or_else(invalid);
+    let octet = || number().and_then(|number| u8::try_from(number).map_err(|_| invalid()));
+    let text = || match value {
+        Value::String(text) => text.as_str().into(),
+        value => value.to_string().into(),
+    };
+    Ok(match name {
+        "content_type" => properties.with_content_type(text()),
+        "content_encoding" => properties.with_content_encoding(text()),
+        "delivery_mode" => properties.with_delivery_mode(octet()?),
+        "priority" => properties.with_priority(octet()?),
+        "correlation_id" => properties.with_correlation_id(text()),
+        "reply_to" => properties.with_reply_to(text()),
+        "expiration" => properties.with_expiration(text()),
+        "message_id" => properties.with_message_id(text()),
+        "timestamp" => properties.with_timestamp(number()?),
+        "type" => properties.with_type(text()),
+        "user_id" => properties.with_user_id(text()),
+        "app_id" => properties.with_app_id(text()),
+        "cluster_id" => properties.with_cluster_id(text()),
+        _ => return Err(format!("unknown property: {name}")),
+    })
+}
+
+/// Converts a json value into its closest amqp representation.
+pub fn amqp_value(value: Value) -> AMQPValue {
+    match value {
+        Value::Null => AMQPValue::Void,
+        Value::Bool(value) => AMQPValue::Boolean(value),
+        Value::Number(number) => match (number.as_i64(), number.as_f64()) {
+            (Some(number), _) => AMQPValue::LongLongInt(number),
+            (None, Some(number)) => AMQPValue::Double(number),
+            (None, None) => AMQPValue::LongString(number.to_string().into()),
+        },
+        Value::String(value) => AMQPValue::LongString(value.into()),
+        Value::Array(values) => AMQPValue::FieldArray(FieldArray::from(
+            values.into_iter().map(amqp_value).collect::<Vec<_>>(),
+        )),
+        Value::Object(values) => {
+            let mut table = FieldTable::default();
+            for (key, value) in values {
+                table.insert(key.into(), amqp_value(value));
+            }
+            AMQPValue::FieldTable(table)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::split_csv;
+
+    #[test]
+    fn splits_plain_fields() {
+        assert_eq!(split_csv("a,b,,c"), ["a", "b", "", "c"]);
+        assert_eq!(split_csv(""), [""]);
+        assert_eq!(split_csv("a,"), ["a", ""]);
+    }
+
+    #[test]
+    fn unquotes_quoted_fields() {
+        assert_eq!(split_csv(r#""a,b",c"#), ["a,b", "c"]);
+        assert_eq!(split_csv(r#""say ""hi""",x"#), [r#"say "hi""#, "x"]);
+        assert_eq!(split_csv(r#""",a"#), ["", "a"]);
+    }
+}
@@ -0,0 +1,51 @@
+//! Transforming json bodies with jq filters.
+use jaq_core::{
+    compile::Filter,
+    data::JustLut,
+    load::{Arena, File, Loader},
+    unwrap_valr, Compiler, Ctx, Native, Vars,
+};
+use jaq_json::{read, Val};
+use std::str::FromStr;
+
+/// A compiled jq filter such as `.payload.order_id`.
+pub struct Jq(Filter<Native<JustLut<Val>>>);
+
+impl FromStr for Jq {
+    type Err = String;
+
+    fn from_str(code: &str) -> Result<Self, Self::Err> {
+        let defs = jaq_core::defs()
+            .chain(jaq_std::defs())
+            .chain(jaq_json::defs());
+        let funs = jaq_core::funs()
+            .chain(jaq_std::funs())
+            .chain(jaq_json::funs());
+        let arena = Arena::default();
+        let modules = Loader::new(defs)
+            .load(&arena, File { code, path: () })
+            .map_err(|errs| format!("invalid jq filter {code}: {errs:?}"))?;
+        let filter = Compiler::default()
+            .with_funs(funs)
+            .compile(modules)
+            .map_err(|errs| format!("invalid jq filter {code}: {errs:?}"))?;
+        Ok(Self(filter))
+    }
+}
+
+impl Jq {
+    /// Runs the filter on a json document, returning each output as compact json.
+    pub fn run(&self, input: &[u8]) -> Result<Vec<String>, String> {
+        let input = read::parse_single(input).map_err(|err| err.to_string())?;
+        let ctx = Ctx::<JustLut<Val>>::new(&self.0.lut, Vars::new([]));
+        self.0
+            .id
+            .run((ctx, input))
+            .map(|output| {
+                unwrap_valr(output)
+                    .map(|output| output.to_string())
+                    .map_err(|err| err.to_string())
+            })
+            .collect()
+    }
+}
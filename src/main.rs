@@ -1,17 +1,63 @@
 //! AMQP command line interface.
-use amq_protocol_types::FieldTable;
+use amq_protocol_types::{AMQPValue, FieldTable, ShortString};
+use compress::Compression;
+use consume::{Consume, Subscribe};
 use core::time::Duration;
-use futures_lite::stream::StreamExt;
+use decode::Codec;
+use get::{Get, Peek};
+use ids::MessageId;
+use input::{Input, Layout, Message};
+use jq::Jq;
 use lapin::{
+    message::BasicReturnMessage,
     options::{
-        BasicAckOptions, BasicConsumeOptions, BasicPublishOptions, BasicQosOptions,
-        BasicRejectOptions,
+        BasicPublishOptions, ConfirmSelectOptions, ExchangeDeclareOptions, ExchangeDeleteOptions,
+        QueueBindOptions, QueueDeclareOptions, QueueDeleteOptions, QueuePurgeOptions,
     },
-    BasicProperties, Channel, Connection, ConnectionProperties,
+    protocol::constants::REPLY_SUCCESS,
+    publisher_confirm::{Confirmation, PublisherConfirm},
+    BasicProperties, Channel, Connection, ConnectionProperties, ExchangeKind, Queue,
 };
 use mimalloc::MiMalloc;
-use std::io::{stdin, stdout, BufRead, Write};
+use rotate::parse_duration;
+use source::SourceOpts;
+use stats::Progress;
+use std::{
+    borrow::Cow,
+    cell::Cell,
+    collections::{BTreeMap, VecDeque},
+    fmt::Display,
+    fs::{File, OpenOptions},
+    io::Write,
+    num::{NonZeroU64, NonZeroUsize},
+    path::PathBuf,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
 use structopt::StructOpt;
+use throttle::{parse_rate, Throttle};
+use tokio::time::Instant;
+use topology::TopologyCmd;
+use trace::Trace;
+
+mod compress;
+mod consume;
+mod decode;
+mod exec;
+mod filter;
+mod get;
+mod ids;
+mod input;
+mod jq;
+mod management;
+mod output;
+mod rotate;
+mod select;
+mod source;
+mod stats;
+mod throttle;
+mod topology;
+mod trace;
 
 /// A fast cross platform allocator.
 #[global_allocator]
@@ -21,7 +67,7 @@ static GLOBAL: MiMalloc = MiMalloc;
 #[tokio::main]
 async fn main() {
     reset_signal_pipe_handler();
-    Opts::from_args().run().await;
+    Box::pin(Opts::from_args().run()).await;
 }
 
 /// Handle pipe output.
@@ -46,144 +92,1343 @@ struct Opts {
 }
 
 impl Opts {
-    /// Connects to rabbitmq and runs the desired command.
+    /// Connects to rabbitmq, runs the desired command and closes the connection, exiting with the status of the
+    /// command.
     async fn run(self) {
         let conn = Connection::connect(&self.addr, ConnectionProperties::default())
             .await
             .unwrap();
-        self.cmd.run(conn.create_channel().await.unwrap()).await;
+        let status = Box::pin(self.cmd.run(&self.addr, &conn)).await;
+        // Consumers reconnect on a fresh connection if this one closed.
+        if conn.status().connected() {
+            conn.close(REPLY_SUCCESS, "OK").await.unwrap();
+        }
+        if status != 0 {
+            std::process::exit(status);
+        }
     }
 }
 
 /// Commands which can be run against rabbitmq broker.
 #[derive(StructOpt)]
+#[allow(clippy::large_enum_variant)]
 enum Cmd {
     /// Reads messages from rabbitmq and writes them line by line to stdout.
-    Consume {
-        /// The queue from which to read.
-        queue: String,
+    Consume(Consume),
 
-        /// Identifies the connection.
-        #[structopt(short, long, default_value = "")]
-        consumer_tag: String,
+    /// Reads messages line by line from stdin or files and writes them to rabbitmq.
+    Publish(Publish),
 
-        /// Whether to acknowledge messages containing newlines.
-        #[structopt(short, long)]
-        newline_error_ack: bool,
+    /// Binds a temporary queue to an exchange and consumes from it.
+    Subscribe(Subscribe),
 
-        /// Whether to acknowledge messages which cannot be parsed as utf-8.
-        #[structopt(short, long)]
-        parse_error_ack: bool,
+    /// Fetches messages one at a time with basic.get, without setting up a consumer.
+    Get(Get),
+
+    /// Prints messages from a queue and puts them back, leaving its contents untouched.
+    Peek(Peek),
+
+    /// Manages queues.
+    Queue(QueueCmd),
+
+    /// Manages exchanges.
+    Exchange(ExchangeCmd),
+
+    /// Binds a queue to an exchange.
+    Bind(Binding),
+
+    /// Removes a binding between a queue and an exchange.
+    Unbind(Binding),
+
+    /// Drops all messages from a queue and prints how many were dropped.
+    Purge {
+        /// The queue to purge.
+        queue: String,
     },
 
-    /// Reads messages line by line from stdin and writes them to rabbitmq.
-    Publish {
-        /// Destination exchange.
-        #[structopt(short, long, default_value = "")]
-        exchange: String,
+    /// Manages dead letter topologies.
+    Dlq(DlqCmd),
+
+    /// Manages whole topologies described in files.
+    Topology(TopologyCmd),
 
-        /// Routing key for all messages.
-        #[structopt(short, long, default_value = "")]
-        routing_key: String,
+    /// Prints messages as they are published and delivered, via the firehose tracer.
+    Trace(Trace),
+
+    /// Prints the message count and consumer count of an existing queue.
+    Inspect {
+        /// The queue to inspect.
+        queue: String,
+
+        /// Whether to print the counts as a json object.
+        #[structopt(short, long)]
+        json: bool,
     },
 }
 
 impl Cmd {
-    /// Loops through the messages line by line.
-    async fn run(self, chan: Channel) {
-        const BATCH_SIZE: u16 = 0x100;
+    /// Dispatches to the desired command, giving the exit status.
+    async fn run(self, addr: &str, conn: &Connection) -> i32 {
+        let chan = conn.create_channel().await.unwrap();
         match self {
-            Self::Consume {
-                queue,
-                consumer_tag,
-                newline_error_ack,
-                parse_error_ack,
-            } => {
-                chan.basic_qos(BATCH_SIZE << 1, BasicQosOptions::default())
+            Self::Consume(consume) => return consume.run(addr, conn, chan).await,
+            Self::Publish(publish) => return publish.run(conn, chan).await,
+            Self::Subscribe(subscribe) => return subscribe.run(addr, conn, chan).await,
+            Self::Get(get) => get.run(chan).await,
+            Self::Peek(peek) => peek.run(chan).await,
+            Self::Queue(cmd) => cmd.run(chan).await,
+            Self::Exchange(cmd) => cmd.run(chan).await,
+            Self::Bind(binding) => binding.bind(chan).await,
+            Self::Unbind(binding) => binding.unbind(chan).await,
+            Self::Dlq(cmd) => cmd.run(chan).await,
+            Self::Topology(cmd) => cmd.run(chan).await,
+            Self::Trace(trace) => trace.run(chan).await,
+            Self::Purge { queue } => {
+                let count = chan
+                    .queue_purge(&queue, QueuePurgeOptions::default())
                     .await
                     .unwrap();
-                let mut consumer = chan
-                    .basic_consume(
+                println!("{count}");
+            }
+            Self::Inspect { queue, json } => {
+                let queue = chan
+                    .queue_declare(
                         &queue,
-                        &consumer_tag,
-                        BasicConsumeOptions::default(),
+                        QueueDeclareOptions {
+                            passive: true,
+                            ..QueueDeclareOptions::default()
+                        },
                         FieldTable::default(),
                     )
                     .await
                     .unwrap();
-                let (mut i, mut acker) = (0, None);
-                loop {
-                    if let Ok(delivery) =
-                        tokio::time::timeout(Duration::new(1, 0), consumer.next()).await
-                    {
-                        let delivery = delivery.unwrap().unwrap();
-                        match std::str::from_utf8(&delivery.data) {
-                            Ok(data) => {
-                                if data.contains('\n') {
-                                    eprintln!("message contains newlines: {}", data);
-                                    if newline_error_ack {
-                                        acker = Some(delivery.acker);
-                                    } else {
-                                        delivery
-                                            .acker
-                                            .reject(BasicRejectOptions::default())
-                                            .await
-                                            .unwrap();
-                                    }
-                                } else {
-                                    acker = Some(delivery.acker);
-                                    println!("{}", data);
-                                }
-                            }
-                            Err(err) => {
-                                eprintln!("parse error: {}", err);
-                                if parse_error_ack {
-                                    acker = Some(delivery.acker);
-                                } else {
-                                    delivery
-                                        .acker
-                                        .reject(BasicRejectOptions::default())
-                                        .await
-                                        .unwrap();
-                                }
-                            }
+                if json {
+                    println!(
+                        "{}",
+                        serde_json::json!({
+                            "queue": queue.name().as_str(),
+                            "messages": queue.message_count(),
+                            "consumers": queue.consumer_count(),
+                        })
+                    );
+                } else {
+                    println!("{}\t{}", queue.message_count(), queue.consumer_count());
+                }
+            }
+        }
+        0
+    }
+}
+
+/// Options for publishing messages.
+#[derive(StructOpt)]
+#[allow(clippy::struct_excessive_bools)]
+struct Publish {
+    /// Destination exchange, taking precedence over the exchange of json input, otherwise the default exchange.
+    #[structopt(short, long)]
+    exchange: Option<String>,
+
+    /// Routing key for all messages, where {file} stands for the name of the file being published from.
+    #[structopt(short, long, default_value = "")]
+    routing_key: String,
+
+    #[structopt(flatten)]
+    source: SourceOpts,
+
+    /// Either text, publishing each line as a body, json, publishing objects as written by consume with `--output
+    /// json`, whose routing key, properties and headers override the options and whose exchange is used unless
+    /// `--exchange` is given, or csv, publishing rows.
+    #[structopt(long, default_value = "text", possible_values = &["text", "json", "csv"])]
+    input: Input,
+
+    /// Column of csv input holding the routing key, counting from 1.
+    #[structopt(long)]
+    rk_column: Option<NonZeroUsize>,
+
+    /// Column of csv input holding the body, counting from 1, otherwise the whole row is published.
+    #[structopt(long)]
+    body_column: Option<NonZeroUsize>,
+
+    /// Takes the routing key of each text line from before the first occurrence of this separator, e.g. '\t',
+    /// publishing the rest as the body.
+    #[structopt(long, parse(from_str = output::unescape), conflicts_with_all = &["hash-field", "rk-from-json"])]
+    rk_separator: Option<String>,
+
+    /// Takes the routing key of each message from this top level json field, for consistent hash exchanges.
+    #[structopt(long)]
+    hash_field: Option<String>,
+
+    /// Takes the routing key of each message from the first output of this jq filter on its json body, e.g.
+    /// `.event.type`.
+    #[structopt(long, conflicts_with = "hash-field")]
+    rk_from_json: Option<Jq>,
+
+    /// Milliseconds to delay delivery by, requires a delayed message exchange.
+    #[structopt(long)]
+    delay_ms: Option<i64>,
+
+    /// Message priority, requires a queue declared with --max-priority, which treats higher priorities as its maximum.
+    #[structopt(long)]
+    priority: Option<u8>,
+
+    /// Milliseconds after which messages expire if they are still in a queue.
+    #[structopt(long)]
+    expiration: Option<u64>,
+
+    /// Whether to publish messages with delivery mode 2, so durable queues keep them across broker restarts.
+    #[structopt(long)]
+    persistent: bool,
+
+    /// Content type property, e.g. application/json.
+    #[structopt(long)]
+    content_type: Option<String>,
+
+    /// Content encoding property, e.g. gzip, for bodies which are already encoded.
+    #[structopt(long)]
+    content_encoding: Option<String>,
+
+    /// Compresses each body with either gzip or zstd, optionally at a level such as zstd:19, setting the content
+    /// encoding to match, except for messages whose input already gives them a content encoding.
+    #[structopt(long, conflicts_with = "content-encoding")]
+    compress: Option<Compression>,
+
+    /// Correlation id property, e.g. the message id of the request being replied to.
+    #[structopt(long)]
+    correlation_id: Option<String>,
+
+    /// Reply to property, naming the queue replies should be sent to.
+    #[structopt(long)]
+    reply_to: Option<String>,
+
+    /// App id property, naming the application which produced the messages.
+    #[structopt(long)]
+    app_id: Option<String>,
+
+    /// User id property, which the broker rejects unless it is the user of the connection.
+    #[structopt(long)]
+    user_id: Option<String>,
+
+    /// Type property, naming the kind of message, e.g. order.created.
+    #[structopt(long = "type")]
+    kind: Option<String>,
+
+    /// Stamps each message with a unique id, either uuid, ulid or seq for its position in the input.
+    #[structopt(long, possible_values = &["uuid", "ulid", "seq"])]
+    message_id: Option<MessageId>,
+
+    /// Whether to set the timestamp property of each message to when it is published.
+    #[structopt(long)]
+    timestamp: bool,
+
+    /// Number of published messages which may await their publisher confirms at once on each channel, where larger
+    /// windows pipeline publishing but report nacks later.
+    #[structopt(long, default_value = "1")]
+    confirm_window: NonZeroUsize,
+
+    /// Number of channels to publish to in turn, each with its own confirm window and transactions, to pipeline
+    /// publishing further at the cost of ordering between messages.
+    #[structopt(long, default_value = "1")]
+    channels: NonZeroUsize,
+
+    /// Whether to publish without publisher confirms, as fast as possible but without knowing whether the broker
+    /// accepted the messages.
+    #[structopt(long, conflicts_with = "mandatory")]
+    no_confirm: bool,
+
+    /// Publishes in transactions instead of with publisher confirms, committing after every this many messages and
+    /// at the end of input.
+    #[structopt(long, conflicts_with_all = &["mandatory", "no-confirm"])]
+    tx_commit_every: Option<NonZeroU64>,
+
+    /// Number of messages the broker nacked so far.
+    #[structopt(skip)]
+    nacked: Cell<u64>,
+
+    /// Whether to have the broker return messages which no queue receives, reporting them and exiting with status 1.
+    #[structopt(long)]
+    mandatory: bool,
+
+    /// Appends returned messages to this file as json lines, which `--input json` can republish, instead of printing
+    /// them to stderr.
+    #[structopt(long, requires = "mandatory")]
+    returned_file: Option<PathBuf>,
+
+    /// The file returned messages are appended to, if given.
+    #[structopt(skip)]
+    returns: Option<File>,
+
+    /// Number of messages returned so far.
+    #[structopt(skip)]
+    returned: Cell<u64>,
+
+    /// Number of messages published so far.
+    #[structopt(skip)]
+    published: Cell<u64>,
+
+    /// Message header in the form key=value[:type], may be repeated.
+    #[structopt(long = "header", number_of_values = 1, parse(try_from_str = parse_arg))]
+    headers: Vec<(ShortString, AMQPValue)>,
+
+    /// Whether to turn backslash escapes such as \n back into the characters they stand for.
+    #[structopt(long)]
+    unescape: bool,
+
+    /// Jq filter to transform each json line with, e.g. `.payload`, publishing each output as a message.
+    #[structopt(long)]
+    jq: Option<Jq>,
+
+    /// Either msgpack or cbor, to convert each json line to that encoding.
+    #[structopt(long, possible_values = &["msgpack", "cbor"])]
+    encode: Option<Codec>,
+
+    /// Prints the message and byte rates to stderr every second.
+    #[structopt(long)]
+    progress: bool,
+
+    /// Most messages to publish per second, e.g. 100 or 0.5, smoothing out bursts.
+    #[structopt(long, parse(try_from_str = parse_rate))]
+    rate: Option<f64>,
+
+    /// Paces publishing to the rate, if given.
+    #[structopt(skip)]
+    throttle: Option<Throttle>,
+
+    /// Stops reading input once publishing for this long, e.g. 5m.
+    #[structopt(long, parse(try_from_str = parse_duration))]
+    duration: Option<Duration>,
+
+    /// Throughput since progress was last reported, if it is.
+    #[structopt(skip)]
+    meter: Option<Progress>,
+}
+
+impl Publish {
+    /// Publishes each line of input as a message, giving the exit status.
+    async fn run(mut self, conn: &Connection, chan: Channel) -> i32 {
+        if self.progress {
+            self.meter = Some(Progress::default());
+        }
+        self.throttle = self.rate.map(Throttle::new);
+        let mut lanes = vec![self.lane(chan).await];
+        for _ in 1..self.channels.get() {
+            lanes.push(self.lane(conn.create_channel().await.unwrap()).await);
+        }
+        if let Some(path) = &self.returned_file {
+            let file = OpenOptions::new().create(true).append(true).open(path);
+            self.returns = Some(file.unwrap());
+        }
+        let properties = self.properties();
+        let layout = Layout {
+            rk_column: self.rk_column,
+            body_column: self.body_column,
+            rk_separator: self.rk_separator.as_deref(),
+        };
+        let deadline = self.duration.map(|duration| Instant::now() + duration);
+        let mut turn = 0;
+        let mut records = self.source.open();
+        while let Some(record) = source::next(&mut records, deadline).await {
+            let record = match record {
+                Ok(record) => record,
+                Err(err) => {
+                    eprintln!("input error: {err}");
+                    continue;
+                }
+            };
+            let undelivered = self.undelivered();
+            let payloads = self.payloads(record.data);
+            let mut complete = payloads.is_some();
+            for payload in payloads.into_iter().flatten() {
+                match self.input.parse(payload, &properties, layout) {
+                    Ok(message) => {
+                        if let Some(throttle) = &self.throttle {
+                            throttle.wait().await;
                         }
-                        i += 1;
-                        if i == BATCH_SIZE {
-                            stdout().flush().unwrap();
-                            acker
-                                .take()
-                                .unwrap()
-                                .ack(BasicAckOptions { multiple: true })
-                                .await
-                                .unwrap();
-                            i = 0;
+                        let (file, lane) = (record.file.as_deref(), &mut lanes[turn]);
+                        if let Some(confirm) = self.publish(&lane.chan, message, file).await {
+                            self.sent(lane, confirm).await;
+                            turn = (turn + 1) % lanes.len();
+                        } else {
+                            complete = false;
                         }
-                    } else if let Some(acker) = acker.take() {
-                        stdout().flush().unwrap();
-                        acker.ack(BasicAckOptions { multiple: true }).await.unwrap();
-                        i = 0;
                     }
+                    Err(err) => {
+                        eprintln!("input error: {err}");
+                        complete = false;
+                    }
+                }
+            }
+            if let Some(path) = &record.watched {
+                for lane in &mut lanes {
+                    self.settle(lane).await;
+                }
+                if complete && self.undelivered() == undelivered {
+                    self.source.published(path);
+                } else {
+                    eprintln!("leaving {} as not all of it was published", path.display());
+                }
+            }
+            if let Some(meter) = self.meter.as_ref().filter(|meter| meter.due()) {
+                meter.report(None);
+            }
+        }
+        for lane in &mut lanes {
+            self.settle(lane).await;
+        }
+        let (returned, nacked) = (self.returned.get(), self.nacked.get());
+        if returned > 0 || nacked > 0 {
+            eprintln!("{returned} messages returned, {nacked} nacked");
+            return UNDELIVERED_EXIT_CODE;
+        }
+        0
+    }
+
+    /// Unescapes and transforms a record into the payloads to publish, unless it cannot be.
+    fn payloads(&self, mut payload: Vec<u8>) -> Option<Vec<Vec<u8>>> {
+        if self.unescape {
+            match String::from_utf8(payload) {
+                Ok(text) => payload = output::unescape(&text).into_bytes(),
+                Err(err) => {
+                    eprintln!("input error: {err}");
+                    return None;
+                }
+            }
+        }
+        match &self.jq {
+            Some(jq) => match jq.run(&payload) {
+                Ok(payloads) => Some(payloads.into_iter().map(String::into_bytes).collect()),
+                Err(err) => {
+                    eprintln!("jq error: {err}");
+                    None
+                }
+            },
+            None => Some(vec![payload]),
+        }
+    }
+
+    /// Publishes a single message unless it has no routing key or cannot be encoded, giving its pending confirm.
+    async fn publish(
+        &self,
+        chan: &Channel,
+        message: Message,
+        file: Option<&str>,
+    ) -> Option<Pending> {
+        let routing_key = match message.routing_key {
+            Some(routing_key) => Cow::Owned(routing_key),
+            None => match self.routing_key(&message.body, file) {
+                Ok(routing_key) => routing_key,
+                Err(err) => {
+                    eprintln!("routing key error: {err}");
+                    return None;
+                }
+            },
+        };
+        let body = match self.encode {
+            Some(codec) => match std::str::from_utf8(&message.body)
+                .map_err(|err| err.to_string())
+                .and_then(|json| codec.encode(json))
+            {
+                Ok(body) => body,
+                Err(err) => {
+                    eprintln!("encoding error: {err}");
+                    return None;
                 }
+            },
+            None => message.body,
+        };
+        self.published.set(self.published.get() + 1);
+        let mut properties = message.properties;
+        let body = match self.compress {
+            Some(compression) if properties.content_encoding().is_none() => {
+                properties = properties.with_content_encoding(compression.encoding().into());
+                compression.compress(&body)
             }
-            Self::Publish {
-                exchange,
-                routing_key,
+            _ => body,
+        };
+        if let Some(message_id) = self
+            .message_id
+            .filter(|_| properties.message_id().is_none())
+        {
+            let message_id = message_id.generate(self.published.get());
+            properties = properties.with_message_id(message_id.into());
+        }
+        if self.timestamp && properties.timestamp().is_none() {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+            properties = properties.with_timestamp(now.as_secs());
+        }
+        let options = BasicPublishOptions {
+            mandatory: self.mandatory,
+            ..BasicPublishOptions::default()
+        };
+        let confirm = chan
+            .basic_publish(
+                self.exchange
+                    .as_deref()
+                    .or(message.exchange.as_deref())
+                    .unwrap_or_default(),
+                &routing_key,
+                options,
+                &body,
+                properties,
+            )
+            .await
+            .unwrap();
+        if let Some(meter) = &self.meter {
+            meter.record(body.len());
+        }
+        Some(Pending {
+            seq: self.published.get(),
+            routing_key: routing_key.into_owned(),
+            confirm,
+        })
+    }
+
+    /// Puts the channel into transaction or confirm mode, as asked, to publish to.
+    async fn lane(&self, chan: Channel) -> Lane {
+        if self.tx_commit_every.is_some() {
+            chan.tx_select().await.unwrap();
+        } else if !self.no_confirm {
+            chan.confirm_select(ConfirmSelectOptions::default())
+                .await
+                .unwrap();
+        }
+        Lane {
+            chan,
+            pending: VecDeque::new(),
+            uncommitted: 0,
+        }
+    }
+
+    /// Tracks a message published to the channel, committing or waiting for confirms once enough are outstanding.
+    async fn sent(&self, lane: &mut Lane, confirm: Pending) {
+        lane.pending.push_back(confirm);
+        lane.uncommitted += 1;
+        if self
+            .tx_commit_every
+            .is_some_and(|every| lane.uncommitted >= every.get())
+        {
+            lane.chan.tx_commit().await.unwrap();
+            lane.uncommitted = 0;
+        }
+        while lane.pending.len() >= self.confirm_window.get() {
+            self.confirmed(lane.pending.pop_front().unwrap()).await;
+        }
+    }
+
+    /// Commits any open transaction on the channel and waits for all its pending confirms.
+    async fn settle(&self, lane: &mut Lane) {
+        if self.tx_commit_every.is_some() && lane.uncommitted > 0 {
+            lane.chan.tx_commit().await.unwrap();
+            lane.uncommitted = 0;
+        }
+        for confirm in lane.pending.drain(..) {
+            self.confirmed(confirm).await;
+        }
+    }
+
+    /// Number of messages the broker returned or nacked so far.
+    fn undelivered(&self) -> u64 {
+        self.returned.get() + self.nacked.get()
+    }
+
+    /// Waits for a message to be confirmed, reporting it if the broker returned or nacked it.
+    async fn confirmed(&self, pending: Pending) {
+        let confirmation = pending.confirm.await.unwrap();
+        if let Confirmation::Nack(_) = confirmation {
+            self.nacked.set(self.nacked.get() + 1);
+            eprintln!(
+                "message {} with routing key {:?} was nacked",
+                pending.seq, pending.routing_key
+            );
+        }
+        if let Some(returned) = confirmation.take_message() {
+            self.report_returned(&returned);
+        }
+    }
+
+    /// Reports a message the broker could not route, to the returned file if given.
+    fn report_returned(&self, returned: &BasicReturnMessage) {
+        self.returned.set(self.returned.get() + 1);
+        let delivery = &returned.delivery;
+        match self.returns.as_ref() {
+            Some(mut file) => {
+                let mut envelope = output::envelope(delivery, None, &delivery.data);
+                envelope["reply_code"] = returned.reply_code.into();
+                envelope["reply_text"] = returned.reply_text.as_str().into();
+                writeln!(file, "{envelope}").unwrap();
+            }
+            None => eprintln!(
+                "returned {} {}: exchange {:?} routing key {:?}",
+                returned.reply_code, returned.reply_text, delivery.exchange, delivery.routing_key
+            ),
+        }
+    }
+
+    /// Builds the properties shared by all messages.
+    fn properties(&self) -> BasicProperties {
+        let mut headers = field_table(self.headers.clone());
+        if let Some(delay) = self.delay_ms {
+            headers.insert("x-delay".into(), AMQPValue::LongLongInt(delay));
+        }
+        let mut properties = BasicProperties::default();
+        if let Some(priority) = self.priority {
+            properties = properties.with_priority(priority);
+        }
+        if self.persistent {
+            properties = properties.with_delivery_mode(PERSISTENT);
+        }
+        if let Some(expiration) = self.expiration {
+            properties = properties.with_expiration(expiration.to_string().into());
+        }
+        if let Some(correlation_id) = &self.correlation_id {
+            properties = properties.with_correlation_id(correlation_id.as_str().into());
+        }
+        if let Some(reply_to) = &self.reply_to {
+            properties = properties.with_reply_to(reply_to.as_str().into());
+        }
+        if let Some(app_id) = &self.app_id {
+            properties = properties.with_app_id(app_id.as_str().into());
+        }
+        if let Some(user_id) = &self.user_id {
+            properties = properties.with_user_id(user_id.as_str().into());
+        }
+        if let Some(kind) = &self.kind {
+            properties = properties.with_type(kind.as_str().into());
+        }
+        if let Some(content_type) = &self.content_type {
+            properties = properties.with_content_type(content_type.as_str().into());
+        }
+        if let Some(content_encoding) = &self.content_encoding {
+            properties = properties.with_content_encoding(content_encoding.as_str().into());
+        }
+        if headers.inner().is_empty() {
+            properties
+        } else {
+            properties.with_headers(headers)
+        }
+    }
+
+    /// Determines the routing key for the given body, read from the given file, if any.
+    fn routing_key(&self, body: &[u8], file: Option<&str>) -> Result<Cow<'_, str>, String> {
+        if let Some(jq) = &self.rk_from_json {
+            let output = jq.run(body)?.into_iter().next();
+            return match output.map(|output| serde_json::from_str(&output)) {
+                Some(Ok(serde_json::Value::String(key))) => Ok(Cow::Owned(key)),
+                Some(Ok(serde_json::Value::Null)) | None => Err(format!(
+                    "no routing key in {}",
+                    String::from_utf8_lossy(body)
+                )),
+                Some(Ok(key)) => Ok(Cow::Owned(key.to_string())),
+                Some(Err(err)) => Err(err.to_string()),
+            };
+        }
+        let Some(field) = &self.hash_field else {
+            return Ok(match file {
+                Some(file) if self.routing_key.contains(FILE) => {
+                    Cow::Owned(self.routing_key.replace(FILE, file))
+                }
+                _ => Cow::Borrowed(&self.routing_key),
+            });
+        };
+        let value: serde_json::Value = serde_json::from_slice(body).map_err(|e| e.to_string())?;
+        match value.get(field) {
+            Some(serde_json::Value::String(key)) => Ok(Cow::Owned(key.clone())),
+            Some(key) => Ok(Cow::Owned(key.to_string())),
+            None => Err(format!(
+                "missing field {field}: {}",
+                String::from_utf8_lossy(body)
+            )),
+        }
+    }
+}
+
+/// Stands for the name of the file being published from in routing keys.
+const FILE: &str = "{file}";
+
+/// Delivery mode of messages which survive broker restarts.
+const PERSISTENT: u8 = 2;
+
+/// Exit status when the broker returned or nacked some of the messages.
+const UNDELIVERED_EXIT_CODE: i32 = 1;
+
+/// A channel messages are published to, along with those still to be confirmed or committed.
+struct Lane {
+    /// The channel to publish to.
+    chan: Channel,
+
+    /// Messages awaiting their confirms, oldest first.
+    pending: VecDeque<Pending>,
+
+    /// Number of messages published since the transaction was last committed.
+    uncommitted: u64,
+}
+
+/// A published message awaiting its publisher confirm.
+struct Pending {
+    /// Position of the message in the input, counting from 1.
+    seq: u64,
+
+    /// Routing key the message was published with.
+    routing_key: String,
+
+    /// Resolves once the broker has confirmed the message.
+    confirm: PublisherConfirm,
+}
+
+/// Identifies a binding between a queue and an exchange.
+#[derive(StructOpt)]
+struct Binding {
+    /// Queue to bind.
+    queue: String,
+
+    /// Exchange to bind to.
+    exchange: String,
+
+    /// Routing key or pattern to match.
+    #[structopt(short, long, default_value = "")]
+    routing_key: String,
+
+    /// Binding argument in the form key=value[:type], may be repeated.
+    #[structopt(long = "arg", number_of_values = 1, parse(try_from_str = parse_arg))]
+    args: Vec<(ShortString, AMQPValue)>,
+}
+
+impl Binding {
+    /// Creates the binding.
+    async fn bind(self, chan: Channel) {
+        chan.queue_bind(
+            &self.queue,
+            &self.exchange,
+            &self.routing_key,
+            QueueBindOptions::default(),
+            field_table(self.args),
+        )
+        .await
+        .unwrap();
+    }
+
+    /// Removes the binding.
+    async fn unbind(self, chan: Channel) {
+        chan.queue_unbind(
+            &self.queue,
+            &self.exchange,
+            &self.routing_key,
+            field_table(self.args),
+        )
+        .await
+        .unwrap();
+    }
+}
+
+/// Properties of a queue to declare.
+#[derive(Clone, StructOpt)]
+struct QueueOpts {
+    /// Whether the queue survives a broker restart.
+    #[structopt(short, long)]
+    durable: bool,
+
+    /// Whether the queue is deleted once its last consumer unsubscribes.
+    #[structopt(short, long)]
+    auto_delete: bool,
+
+    /// Queue type, e.g. classic, quorum or stream, non classic queues are always durable.
+    #[structopt(long)]
+    queue_type: Option<String>,
+
+    /// Redeliveries after which messages are dropped or dead lettered, quorum queues only.
+    #[structopt(long)]
+    delivery_limit: Option<u32>,
+
+    /// Either at-most-once or at-least-once, quorum queues only.
+    #[structopt(long, possible_values = &["at-most-once", "at-least-once"])]
+    dead_letter_strategy: Option<String>,
+
+    /// Maximum number of ready messages in the queue.
+    #[structopt(long)]
+    max_length: Option<u32>,
+
+    /// Maximum total body size of ready messages in the queue.
+    #[structopt(long)]
+    max_length_bytes: Option<u64>,
+
+    /// What to do once the queue is full.
+    #[structopt(long, possible_values = &["drop-head", "reject-publish", "reject-publish-dlx"])]
+    overflow: Option<String>,
+
+    /// Milliseconds after which messages in the queue expire.
+    #[structopt(long)]
+    message_ttl: Option<u32>,
+
+    /// Highest message priority supported by the queue.
+    #[structopt(long)]
+    max_priority: Option<u8>,
+
+    /// Whether only one consumer at a time receives messages, with the others on standby.
+    #[structopt(long)]
+    single_active_consumer: bool,
+
+    /// Queue argument in the form key=value[:type], may be repeated.
+    #[structopt(long = "arg", number_of_values = 1, parse(try_from_str = parse_arg))]
+    args: Vec<(ShortString, AMQPValue)>,
+}
+
+impl QueueOpts {
+    /// Declares the queue, applying these properties on top of the given options.
+    async fn declare(self, chan: &Channel, name: &str, options: QueueDeclareOptions) -> Queue {
+        let durable = self.durable || self.queue_type.as_ref().is_some_and(|t| t != "classic");
+        let mut args = field_table(self.args);
+        let int = |value: u64| AMQPValue::LongLongInt(value.try_into().unwrap_or(i64::MAX));
+        let string = |value: String| AMQPValue::LongString(value.into());
+        for (key, value) in [
+            ("x-queue-type", self.queue_type.map(string)),
+            (
+                "x-delivery-limit",
+                self.delivery_limit.map(u64::from).map(int),
+            ),
+            (
+                "x-dead-letter-strategy",
+                self.dead_letter_strategy.map(string),
+            ),
+            ("x-max-length", self.max_length.map(u64::from).map(int)),
+            ("x-max-length-bytes", self.max_length_bytes.map(int)),
+            ("x-overflow", self.overflow.map(string)),
+            ("x-message-ttl", self.message_ttl.map(u64::from).map(int)),
+            ("x-max-priority", self.max_priority.map(u64::from).map(int)),
+            (
+                "x-single-active-consumer",
+                self.single_active_consumer
+                    .then_some(AMQPValue::Boolean(true)),
+            ),
+        ] {
+            if let Some(value) = value {
+                args.insert(key.into(), value);
+            }
+        }
+        chan.queue_declare(
+            name,
+            QueueDeclareOptions {
+                durable,
+                auto_delete: self.auto_delete,
+                ..options
+            },
+            args,
+        )
+        .await
+        .unwrap()
+    }
+}
+
+/// Queue management commands.
+#[derive(StructOpt)]
+enum QueueCmd {
+    /// Declares a queue and prints its name, message count and consumer count.
+    Declare {
+        /// Queue name, leave empty to have the broker generate one.
+        #[structopt(default_value = "")]
+        name: String,
+
+        /// Whether the queue is only accessible by this connection.
+        #[structopt(short, long)]
+        exclusive: bool,
+
+        /// Whether to only check that the queue exists.
+        #[structopt(short, long)]
+        passive: bool,
+
+        #[structopt(flatten)]
+        opts: QueueOpts,
+    },
+
+    /// Deletes a queue and prints the number of messages deleted.
+    Delete {
+        /// Queue name.
+        name: String,
+
+        /// Whether to only delete the queue if it has no consumers.
+        #[structopt(short = "u", long)]
+        if_unused: bool,
+
+        /// Whether to only delete the queue if it has no messages.
+        #[structopt(short = "e", long)]
+        if_empty: bool,
+    },
+}
+
+impl QueueCmd {
+    /// Runs the queue command and prints the result.
+    async fn run(self, chan: Channel) {
+        match self {
+            Self::Declare {
+                name,
+                exclusive,
+                passive,
+                opts,
+            } => {
+                let queue = opts
+                    .declare(
+                        &chan,
+                        &name,
+                        QueueDeclareOptions {
+                            passive,
+                            exclusive,
+                            ..QueueDeclareOptions::default()
+                        },
+                    )
+                    .await;
+                println!(
+                    "{}\t{}\t{}",
+                    queue.name(),
+                    queue.message_count(),
+                    queue.consumer_count()
+                );
+            }
+            Self::Delete {
+                name,
+                if_unused,
+                if_empty,
             } => {
-                for payload in stdin().lock().lines() {
-                    chan.basic_publish(
-                        &exchange,
-                        &routing_key,
-                        BasicPublishOptions::default(),
-                        payload.unwrap().as_bytes(),
-                        BasicProperties::default(),
+                let count = chan
+                    .queue_delete(
+                        &name,
+                        QueueDeleteOptions {
+                            if_unused,
+                            if_empty,
+                            nowait: false,
+                        },
                     )
                     .await
-                    .unwrap()
+                    .unwrap();
+                println!("{count}");
+            }
+        }
+    }
+}
+
+/// Exchange management commands.
+#[derive(StructOpt)]
+enum ExchangeCmd {
+    /// Declares an exchange.
+    Declare {
+        /// Exchange name.
+        name: String,
+
+        /// Exchange type, e.g. direct, fanout, topic, headers or a plugin type.
+        #[structopt(short = "t", long = "type", default_value = "direct", parse(from_str = parse_exchange_kind))]
+        kind: ExchangeKind,
+
+        /// Whether the exchange survives a broker restart.
+        #[structopt(short, long)]
+        durable: bool,
+
+        /// Whether the exchange is deleted once its last binding is removed.
+        #[structopt(short, long)]
+        auto_delete: bool,
+
+        /// Whether the exchange can only be published to by other exchanges.
+        #[structopt(short, long)]
+        internal: bool,
+
+        /// Whether to only check that the exchange exists.
+        #[structopt(short, long)]
+        passive: bool,
+
+        /// Declares a fanout exchange and queue of this name to capture unroutable messages.
+        #[structopt(long)]
+        alternate_exchange: Option<String>,
+
+        /// Exchange argument in the form key=value[:type], may be repeated.
+        #[structopt(long = "arg", number_of_values = 1, parse(try_from_str = parse_arg))]
+        args: Vec<(ShortString, AMQPValue)>,
+    },
+
+    /// Declares a consistent hash exchange and binds queues to it.
+    ConsistentHash(ConsistentHash),
+
+    /// Declares an exchange which holds messages until their x-delay header has elapsed.
+    Delayed(Delayed),
+
+    /// Deletes an exchange.
+    Delete {
+        /// Exchange name.
+        name: String,
+
+        /// Whether to only delete the exchange if it has no bindings.
+        #[structopt(short = "u", long)]
+        if_unused: bool,
+    },
+}
+
+impl ExchangeCmd {
+    /// Runs the exchange command.
+    async fn run(self, chan: Channel) {
+        match self {
+            Self::Declare {
+                name,
+                kind,
+                durable,
+                auto_delete,
+                internal,
+                passive,
+                alternate_exchange,
+                args,
+            } => {
+                let mut args = field_table(args);
+                if let Some(alternate) = alternate_exchange {
+                    declare_alternate_exchange(&chan, &alternate, durable).await;
+                    args.insert(
+                        "alternate-exchange".into(),
+                        AMQPValue::LongString(alternate.into()),
+                    );
+                }
+                chan.exchange_declare(
+                    &name,
+                    kind,
+                    ExchangeDeclareOptions {
+                        passive,
+                        durable,
+                        auto_delete,
+                        internal,
+                        nowait: false,
+                    },
+                    args,
+                )
+                .await
+                .unwrap();
+            }
+            Self::ConsistentHash(exchange) => exchange.declare(&chan).await,
+            Self::Delayed(exchange) => exchange.declare(&chan).await,
+            Self::Delete { name, if_unused } => {
+                chan.exchange_delete(
+                    &name,
+                    ExchangeDeleteOptions {
+                        if_unused,
+                        nowait: false,
+                    },
+                )
+                .await
+                .unwrap();
+            }
+        }
+    }
+}
+
+/// Dead letter topology commands.
+#[derive(StructOpt)]
+enum DlqCmd {
+    /// Declares a work queue along with its dead letter exchange and queue.
+    Setup {
+        /// The work queue.
+        queue: String,
+
+        /// Dead letter exchange, defaults to the work queue name suffixed with .dlx.
+        #[structopt(long)]
+        dlx: Option<String>,
+
+        /// Dead letter queue, defaults to the work queue name suffixed with .dlq.
+        #[structopt(long)]
+        dlq: Option<String>,
+
+        /// Milliseconds after which messages in the work queue are dead lettered.
+        #[structopt(long)]
+        message_ttl: Option<u32>,
+
+        /// Redeliveries after which messages are dead lettered, makes the work queue a durable quorum queue.
+        #[structopt(long)]
+        max_retries: Option<u32>,
+
+        /// Whether the queues and exchange survive a broker restart.
+        #[structopt(short, long)]
+        durable: bool,
+    },
+}
+
+impl DlqCmd {
+    /// Runs the dead letter command.
+    async fn run(self, chan: Channel) {
+        match self {
+            Self::Setup {
+                queue,
+                dlx,
+                dlq,
+                message_ttl,
+                max_retries,
+                durable,
+            } => {
+                let dlx = dlx.unwrap_or_else(|| format!("{queue}.dlx"));
+                let dlq = dlq.unwrap_or_else(|| format!("{queue}.dlq"));
+                chan.exchange_declare(
+                    &dlx,
+                    ExchangeKind::Direct,
+                    ExchangeDeclareOptions {
+                        durable,
+                        ..ExchangeDeclareOptions::default()
+                    },
+                    FieldTable::default(),
+                )
+                .await
+                .unwrap();
+                let options = QueueDeclareOptions {
+                    durable,
+                    ..QueueDeclareOptions::default()
+                };
+                chan.queue_declare(&dlq, options, FieldTable::default())
                     .await
                     .unwrap();
+                chan.queue_bind(
+                    &dlq,
+                    &dlx,
+                    &queue,
+                    QueueBindOptions::default(),
+                    FieldTable::default(),
+                )
+                .await
+                .unwrap();
+                let mut args = FieldTable::default();
+                args.insert(
+                    "x-dead-letter-exchange".into(),
+                    AMQPValue::LongString(dlx.into()),
+                );
+                args.insert(
+                    "x-dead-letter-routing-key".into(),
+                    AMQPValue::LongString(queue.as_str().into()),
+                );
+                if let Some(ttl) = message_ttl {
+                    args.insert("x-message-ttl".into(), AMQPValue::LongLongInt(ttl.into()));
                 }
+                let mut options = options;
+                if let Some(retries) = max_retries {
+                    args.insert(
+                        "x-queue-type".into(),
+                        AMQPValue::LongString("quorum".into()),
+                    );
+                    args.insert(
+                        "x-delivery-limit".into(),
+                        AMQPValue::LongLongInt(retries.into()),
+                    );
+                    options.durable = true;
+                }
+                chan.queue_declare(&queue, options, args).await.unwrap();
             }
         }
     }
 }
+
+/// A consistent hash exchange.
+#[derive(StructOpt)]
+struct ConsistentHash {
+    /// Exchange name.
+    name: String,
+
+    /// Queues to bind, may be repeated.
+    #[structopt(short, long = "queue", number_of_values = 1)]
+    queues: Vec<String>,
+
+    /// Relative share of the hash space assigned to each queue.
+    #[structopt(short, long, default_value = "1")]
+    weight: u32,
+
+    /// Hashes this header instead of the routing key.
+    #[structopt(long)]
+    hash_header: Option<String>,
+
+    /// Whether the exchange survives a broker restart.
+    #[structopt(short, long)]
+    durable: bool,
+}
+
+impl ConsistentHash {
+    /// Declares the exchange and binds the queues with their weights.
+    async fn declare(self, chan: &Channel) {
+        let mut args = FieldTable::default();
+        if let Some(header) = self.hash_header {
+            args.insert("hash-header".into(), AMQPValue::LongString(header.into()));
+        }
+        chan.exchange_declare(
+            &self.name,
+            ExchangeKind::Custom("x-consistent-hash".into()),
+            ExchangeDeclareOptions {
+                durable: self.durable,
+                ..ExchangeDeclareOptions::default()
+            },
+            args,
+        )
+        .await
+        .unwrap();
+        let weight = self.weight.to_string();
+        for queue in self.queues {
+            chan.queue_bind(
+                &queue,
+                &self.name,
+                &weight,
+                QueueBindOptions::default(),
+                FieldTable::default(),
+            )
+            .await
+            .unwrap();
+        }
+    }
+}
+
+/// A delayed message exchange.
+#[derive(StructOpt)]
+struct Delayed {
+    /// Exchange name.
+    name: String,
+
+    /// Exchange type used to route messages once their delay has elapsed.
+    #[structopt(short = "t", long = "type", default_value = "direct")]
+    kind: String,
+
+    /// Whether the exchange survives a broker restart.
+    #[structopt(short, long)]
+    durable: bool,
+}
+
+impl Delayed {
+    /// Declares the exchange.
+    async fn declare(self, chan: &Channel) {
+        let mut args = FieldTable::default();
+        args.insert(
+            "x-delayed-type".into(),
+            AMQPValue::LongString(self.kind.into()),
+        );
+        chan.exchange_declare(
+            &self.name,
+            ExchangeKind::Custom("x-delayed-message".into()),
+            ExchangeDeclareOptions {
+                durable: self.durable,
+                ..ExchangeDeclareOptions::default()
+            },
+            args,
+        )
+        .await
+        .unwrap();
+    }
+}
+
+/// Declares a fanout exchange bound to a queue of the same name.
+async fn declare_alternate_exchange(chan: &Channel, name: &str, durable: bool) {
+    chan.exchange_declare(
+        name,
+        ExchangeKind::Fanout,
+        ExchangeDeclareOptions {
+            durable,
+            ..ExchangeDeclareOptions::default()
+        },
+        FieldTable::default(),
+    )
+    .await
+    .unwrap();
+    chan.queue_declare(
+        name,
+        QueueDeclareOptions {
+            durable,
+            ..QueueDeclareOptions::default()
+        },
+        FieldTable::default(),
+    )
+    .await
+    .unwrap();
+    chan.queue_bind(
+        name,
+        name,
+        "",
+        QueueBindOptions::default(),
+        FieldTable::default(),
+    )
+    .await
+    .unwrap();
+}
+
+/// Maps an exchange type name onto the builtin kinds, falling back to a custom kind.
+fn parse_exchange_kind(kind: &str) -> ExchangeKind {
+    match kind {
+        "direct" => ExchangeKind::Direct,
+        "fanout" => ExchangeKind::Fanout,
+        "headers" => ExchangeKind::Headers,
+        "topic" => ExchangeKind::Topic,
+        _ => ExchangeKind::Custom(kind.to_owned()),
+    }
+}
+
+/// Parses a key=value[:type] argument, where type is one of str, int, float, bool or timestamp.
+fn parse_arg(arg: &str) -> Result<(ShortString, AMQPValue), String> {
+    let (key, value) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected key=value[:type]: {arg}"))?;
+    Ok((key.into(), parse_value(value)?))
+}
+
+/// Parses a value with an optional type suffix, defaulting to a string.
+fn parse_value(value: &str) -> Result<AMQPValue, String> {
+    fn parse<T: FromStr>(value: &str) -> Result<T, String>
+    where
+        T::Err: Display,
+    {
+        value
+            .parse()
+            .map_err(|err| format!("invalid value {value}: {err}"))
+    }
+    Ok(match value.rsplit_once(':') {
+        Some((value, "str")) => AMQPValue::LongString(value.into()),
+        Some((value, "int")) => AMQPValue::LongLongInt(parse(value)?),
+        Some((value, "float")) => AMQPValue::Double(parse(value)?),
+        Some((value, "bool")) => AMQPValue::Boolean(parse(value)?),
+        Some((value, "timestamp")) => AMQPValue::Timestamp(parse(value)?),
+        _ => AMQPValue::LongString(value.into()),
+    })
+}
+
+/// Collects parsed arguments into a field table.
+fn field_table(args: Vec<(ShortString, AMQPValue)>) -> FieldTable {
+    args.into_iter().collect::<BTreeMap<_, _>>().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_arg, parse_value};
+    use amq_protocol_types::AMQPValue;
+
+    #[test]
+    fn parses_typed_values() {
+        assert_eq!(parse_value("10:int"), Ok(AMQPValue::LongLongInt(10)));
+        assert_eq!(parse_value("-1.5:float"), Ok(AMQPValue::Double(-1.5)));
+        assert_eq!(parse_value("true:bool"), Ok(AMQPValue::Boolean(true)));
+        assert_eq!(
+            parse_value("1700000000:timestamp"),
+            Ok(AMQPValue::Timestamp(1_700_000_000))
+        );
+        assert_eq!(
+            parse_value("10:str"),
+            Ok(AMQPValue::LongString("10".into()))
+        );
+    }
+
+    #[test]
+    fn defaults_to_strings() {
+        assert_eq!(parse_value("10"), Ok(AMQPValue::LongString("10".into())));
+        assert_eq!(parse_value("a:b"), Ok(AMQPValue::LongString("a:b".into())));
+        assert_eq!(
+            parse_value("a:b:int:str"),
+            Ok(AMQPValue::LongString("a:b:int".into()))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_typed_values() {
+        assert!(parse_value("ten:int").is_err());
+        assert!(parse_value("yes:bool").is_err());
+        assert!(parse_value("-1:timestamp").is_err());
+    }
+
+    #[test]
+    fn parses_arguments() {
+        let (key, value) = parse_arg("x-max-length=100:int").unwrap();
+        assert_eq!(key.as_str(), "x-max-length");
+        assert_eq!(value, AMQPValue::LongLongInt(100));
+        assert_eq!(
+            parse_arg("a=b=c").unwrap().1,
+            AMQPValue::LongString("b=c".into())
+        );
+        assert!(parse_arg("novalue").is_err());
+    }
+}
@@ -0,0 +1,65 @@
+//! Talking to the rabbitmq management api.
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use structopt::StructOpt;
+
+/// Options for connecting to the management api.
+#[derive(StructOpt)]
+pub struct ManagementOpts {
+    /// Management api address.
+    #[structopt(
+        short = "u",
+        long = "management-url",
+        alias = "url",
+        default_value = "http://localhost:15672"
+    )]
+    url: String,
+
+    /// Management api user.
+    #[structopt(long, default_value = "guest")]
+    user: String,
+
+    /// Management api password.
+    #[structopt(long, default_value = "guest")]
+    password: String,
+
+    /// The vhost to look at.
+    #[structopt(long, default_value = "/")]
+    vhost: String,
+}
+
+impl ManagementOpts {
+    /// Fetches a resource of the vhost, e.g. definitions or queues.
+    pub fn get<T: DeserializeOwned>(&self, resource: &str) -> T {
+        ureq::get(&self.resource(resource))
+            .set("Authorization", &self.authorization())
+            .call()
+            .unwrap()
+            .into_json()
+            .unwrap()
+    }
+
+    /// Updates a resource of the vhost, e.g. vhosts to change its settings.
+    pub fn put(&self, resource: &str, body: &Value) {
+        ureq::put(&self.resource(resource))
+            .set("Authorization", &self.authorization())
+            .send_json(body)
+            .unwrap();
+    }
+
+    /// The address of a resource of the vhost.
+    fn resource(&self, resource: &str) -> String {
+        format!(
+            "{}/api/{resource}/{}",
+            self.url.trim_end_matches('/'),
+            self.vhost.replace('/', "%2F")
+        )
+    }
+
+    /// The basic authorization header value.
+    fn authorization(&self) -> String {
+        let auth = STANDARD.encode(format!("{}:{}", self.user, self.password));
+        format!("Basic {auth}")
+    }
+}
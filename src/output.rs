@@ -0,0 +1,1161 @@
+//! Writing consumed messages out.
+use crate::{
+    compress::decompress,
+    decode::DecodeOpts,
+    jq::Jq,
+    rotate::{parse_duration, parse_size, Rotating},
+};
+use amq_protocol_types::AMQPValue;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use core::time::Duration;
+use lapin::message::Delivery;
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    collections::HashMap,
+    fmt::{self, Display, Formatter, Write as _},
+    fs::{self, File, OpenOptions},
+    io::{self, stdout, BufWriter, LineWriter, Stdout, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    str::Utf8Error,
+    time::Instant,
+};
+use structopt::StructOpt;
+
+/// Options controlling how messages are written out.
+#[derive(StructOpt)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct OutputOpts {
+    #[structopt(flatten)]
+    decode: DecodeOpts,
+
+    /// Jq filter to transform json bodies with, e.g. `.payload.order_id`, writing each output as a record.
+    #[structopt(long)]
+    jq: Option<Jq>,
+
+    /// Whether to leave gzip, deflate and zstd content encoded bodies compressed.
+    #[structopt(long)]
+    no_decompress: bool,
+
+    /// Whether to indent json bodies, so each record spans several lines, leaving other bodies as is.
+    #[structopt(long)]
+    pretty: bool,
+
+    /// Whether to sort the keys of json objects when pretty printing.
+    #[structopt(long, requires = "pretty")]
+    sort_keys: bool,
+
+    /// Template for each line, e.g. `{routing_key}\t{timestamp}\t{headers.foo}\t{body}`.
+    #[structopt(long)]
+    format: Option<Template>,
+
+    /// Whether to precede each body with tab separated columns for the exchange, routing key, delivery tag, redelivered
+    /// flag and body size.
+    #[structopt(long, conflicts_with_all = &["format", "output"])]
+    columns: bool,
+
+    /// Either text, writing just the body, which is the default, or json, writing an object with the body and metadata.
+    #[structopt(long, possible_values = &["text", "json"], conflicts_with = "format")]
+    output: Option<Format>,
+
+    /// Either utf8, writing the body as is, base64 or hex, so binary bodies can be written, or xxd, for an annotated dump.
+    #[structopt(long, default_value = "utf8", possible_values = &["utf8", "base64", "hex", "xxd"])]
+    encode: Encoding,
+
+    /// What to terminate each record with, either newline or nul.
+    #[structopt(long, default_value = "newline", possible_values = &["newline", "nul"])]
+    delimiter: Delimiter,
+
+    /// Either delimited, or length-prefix to precede each raw body with its 4 byte big endian length.
+    #[structopt(long, default_value = "delimited", possible_values = &["delimited", "length-prefix"])]
+    framing: Framing,
+
+    /// Whether to escape backslashes, newlines, carriage returns and nuls in utf8 bodies.
+    #[structopt(long)]
+    escape: bool,
+
+    /// File descriptor to write a json object with each message's metadata to, e.g. 3.
+    #[structopt(long, conflicts_with = "props-file")]
+    props_fd: Option<i32>,
+
+    /// File to write a json object with each message's metadata to.
+    #[structopt(long)]
+    props_file: Option<PathBuf>,
+
+    /// Directory to write each message to as its own file, named by message id or delivery tag and numbered when the
+    /// name is taken, with its metadata alongside.
+    #[structopt(long)]
+    out_dir: Option<PathBuf>,
+
+    /// Either routing-key or a header name, writing records to a file per value in --partition-dir, or a directory per
+    /// value with --out-dir.
+    #[structopt(long)]
+    partition_by: Option<Partition>,
+
+    /// Directory holding the file of each partition, which must be given unless partitioning --out-dir.
+    #[structopt(long, requires = "partition-by", conflicts_with = "out-dir")]
+    partition_dir: Option<PathBuf>,
+
+    /// File to write records to instead of stdout.
+    #[structopt(long, conflicts_with_all = &["out-dir", "partition-by"])]
+    out_file: Option<PathBuf>,
+
+    /// Moves the output file aside before it grows beyond this size, e.g. 100M.
+    #[structopt(long, requires = "out-file", parse(try_from_str = parse_size))]
+    rotate_size: Option<u64>,
+
+    /// Moves the output file aside once it has been written to for this long, e.g. 1h.
+    #[structopt(long, requires = "out-file", parse(try_from_str = parse_duration))]
+    rotate_interval: Option<Duration>,
+
+    /// Whether to gzip output files once they have been moved aside.
+    #[structopt(long, requires = "out-file")]
+    gzip: bool,
+
+    /// File to also append records to, while still writing them out as usual.
+    #[structopt(long)]
+    tee: Option<PathBuf>,
+
+    /// The tee file once opened.
+    #[structopt(skip)]
+    tee_file: Option<File>,
+
+    /// The output file once opened.
+    #[structopt(skip)]
+    out: Option<RefCell<Rotating>>,
+
+    /// Files records are appended to, by partition.
+    #[structopt(skip)]
+    partitions: RefCell<HashMap<String, File>>,
+
+    /// Where metadata is written once opened.
+    #[structopt(skip)]
+    props: Option<RefCell<LineWriter<File>>>,
+
+    /// When to flush records written to stdout, either every-message, every-N records, e.g. every-100, or every-Nms,
+    /// e.g. every-50ms, records are always flushed before messages are acknowledged.
+    #[structopt(long = "flush", default_value = "every-message")]
+    flush_policy: Flush,
+
+    /// Records written to stdout which may not have been flushed yet.
+    #[structopt(skip)]
+    stdout: RefCell<Option<Buffered>>,
+}
+
+/// When to flush stdout.
+#[derive(Clone, Copy)]
+enum Flush {
+    /// After each record.
+    Message,
+
+    /// After this many records.
+    Records(u64),
+
+    /// Once a record has waited this long since the last flush.
+    Interval(Duration),
+}
+
+impl FromStr for Flush {
+    type Err = String;
+
+    fn from_str(flush: &str) -> Result<Self, Self::Err> {
+        let unknown = || format!("unknown flush policy: {flush}");
+        let every = flush.strip_prefix("every-").ok_or_else(unknown)?;
+        if every == "message" {
+            return Ok(Self::Message);
+        }
+        if let Some(millis) = every.strip_suffix("ms") {
+            let millis = millis.parse().map_err(|_| unknown())?;
+            return Ok(Self::Interval(Duration::from_millis(millis)));
+        }
+        match every.parse() {
+            Ok(0) | Err(_) => Err(unknown()),
+            Ok(records) => Ok(Self::Records(records)),
+        }
+    }
+}
+
+/// Stdout with a buffer which is flushed according to the policy.
+struct Buffered {
+    /// The buffered stream.
+    out: BufWriter<Stdout>,
+
+    /// Number of records written since the last flush.
+    records: u64,
+
+    /// When stdout was last flushed.
+    flushed: Instant,
+}
+
+impl Default for Buffered {
+    fn default() -> Self {
+        Self {
+            out: BufWriter::with_capacity(1 << 16, stdout()),
+            records: 0,
+            flushed: Instant::now(),
+        }
+    }
+}
+
+impl Buffered {
+    /// Counts a written record, flushing if the policy says so.
+    fn written(&mut self, policy: Flush) -> io::Result<()> {
+        self.records += 1;
+        let due = match policy {
+            Flush::Message => true,
+            Flush::Records(records) => self.records >= records,
+            Flush::Interval(interval) => self.flushed.elapsed() >= interval,
+        };
+        if due {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Flushes everything written so far.
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()?;
+        self.records = 0;
+        self.flushed = Instant::now();
+        Ok(())
+    }
+}
+
+/// How records are told apart.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// Records are followed by the delimiter.
+    Delimited,
+
+    /// Records are preceded by their length.
+    LengthPrefix,
+}
+
+impl FromStr for Framing {
+    type Err = String;
+
+    fn from_str(framing: &str) -> Result<Self, Self::Err> {
+        match framing {
+            "delimited" => Ok(Self::Delimited),
+            "length-prefix" => Ok(Self::LengthPrefix),
+            _ => Err(format!("unknown framing: {framing}")),
+        }
+    }
+}
+
+/// What separates records.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Delimiter {
+    /// Records are lines.
+    Newline,
+
+    /// Records are nul terminated, so they can contain newlines.
+    Nul,
+}
+
+impl FromStr for Delimiter {
+    type Err = String;
+
+    fn from_str(delimiter: &str) -> Result<Self, Self::Err> {
+        match delimiter {
+            "newline" => Ok(Self::Newline),
+            "nul" => Ok(Self::Nul),
+            _ => Err(format!("unknown delimiter: {delimiter}")),
+        }
+    }
+}
+
+impl Delimiter {
+    /// The byte terminating each record.
+    pub fn byte(self) -> u8 {
+        match self {
+            Self::Newline => b'\n',
+            Self::Nul => b'\0',
+        }
+    }
+}
+
+/// How the body is turned into text.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    /// Bodies must already be valid utf-8.
+    Utf8,
+
+    /// Bodies are base64 encoded.
+    Base64,
+
+    /// Bodies are hex encoded.
+    Hex,
+
+    /// Bodies are shown as an xxd style dump over several lines.
+    Xxd,
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(encoding: &str) -> Result<Self, Self::Err> {
+        match encoding {
+            "utf8" => Ok(Self::Utf8),
+            "base64" => Ok(Self::Base64),
+            "hex" => Ok(Self::Hex),
+            "xxd" => Ok(Self::Xxd),
+            _ => Err(format!("unknown encoding: {encoding}")),
+        }
+    }
+}
+
+impl Encoding {
+    /// Turns the body into text.
+    fn encode(self, data: &[u8]) -> Result<Cow<'_, str>, Error> {
+        match self {
+            Self::Utf8 => std::str::from_utf8(data)
+                .map(Cow::Borrowed)
+                .map_err(Error::InvalidUtf8),
+            Self::Base64 => Ok(Cow::Owned(STANDARD.encode(data))),
+            Self::Hex => Ok(Cow::Owned(hex(data))),
+            Self::Xxd => Ok(Cow::Owned(xxd(data))),
+        }
+    }
+}
+
+/// Indents the body if it is json, optionally sorting object keys, otherwise giving none.
+fn pretty(body: &[u8], sort_keys: bool) -> Option<Vec<u8>> {
+    let mut pretty = vec![];
+    if sort_keys {
+        let value: serde_json::Value = serde_json::from_slice(body).ok()?;
+        serde_json::to_writer_pretty(&mut pretty, &value).ok()?;
+    } else {
+        let mut deserializer = serde_json::Deserializer::from_slice(body);
+        let mut serializer = serde_json::Serializer::pretty(&mut pretty);
+        serde_transcode::transcode(&mut deserializer, &mut serializer).ok()?;
+        deserializer.end().ok()?;
+    }
+    Some(pretty)
+}
+
+/// Replaces characters which could be mistaken for delimiters with backslash escapes.
+fn escape(data: &str) -> String {
+    let mut escaped = String::with_capacity(data.len());
+    for c in data.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\0' => escaped.push_str("\\0"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Reverses the backslash escapes added when consuming with escaping enabled.
+pub fn unescape(data: &str) -> String {
+    let mut unescaped = String::with_capacity(data.len());
+    let mut chars = data.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some('0') => unescaped.push('\0'),
+            Some(c) => unescaped.push(c),
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+/// Encodes the bytes as lowercase hex.
+fn hex(data: &[u8]) -> String {
+    let mut hex = String::with_capacity(data.len() << 1);
+    for byte in data {
+        write!(hex, "{byte:02x}").unwrap();
+    }
+    hex
+}
+
+/// Dumps the bytes sixteen per line with their offsets and printable characters, like xxd.
+fn xxd(data: &[u8]) -> String {
+    let mut dump = String::new();
+    for (i, line) in data.chunks(16).enumerate() {
+        if i > 0 {
+            dump.push('\n');
+        }
+        write!(dump, "{:08x}:", i << 4).unwrap();
+        for (j, byte) in line.iter().enumerate() {
+            if j % 2 == 0 {
+                dump.push(' ');
+            }
+            write!(dump, "{byte:02x}").unwrap();
+        }
+        let width = 40 - (line.len() << 1) - line.len().div_ceil(2);
+        write!(dump, "{:width$}  ", "").unwrap();
+        dump.extend(line.iter().map(|&byte| {
+            if byte.is_ascii_graphic() || byte == b' ' {
+                char::from(byte)
+            } else {
+                '.'
+            }
+        }));
+    }
+    dump
+}
+
+/// How each message is represented.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Format {
+    /// The message body as is.
+    Text,
+
+    /// A json object with the body and all its metadata.
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("unknown output format: {format}")),
+        }
+    }
+}
+
+/// Reasons a message cannot be written out.
+#[derive(Debug)]
+pub enum Error {
+    /// The message contains the delimiter so cannot be written as a single record.
+    Delimiter(String),
+
+    /// The message is not valid utf-8.
+    InvalidUtf8(Utf8Error),
+
+    /// The message cannot be decompressed according to its content encoding.
+    Decompress(String),
+
+    /// The message cannot be decoded into json.
+    Decode(String),
+
+    /// The message is not json or the jq filter failed on it.
+    Jq(String),
+
+    /// The record could not be written to stdout, e.g. because the pipe is closed.
+    Write(io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Delimiter(data) => write!(f, "message contains delimiter: {data}"),
+            Self::InvalidUtf8(err) => write!(f, "parse error: {err}"),
+            Self::Decompress(err) | Self::Decode(err) => f.write_str(err),
+            Self::Jq(err) => write!(f, "jq error: {err}"),
+            Self::Write(err) => write!(f, "write error: {err}"),
+        }
+    }
+}
+
+impl OutputOpts {
+    /// Opens the metadata stream, if any.
+    pub fn open(&mut self) {
+        self.decode.open();
+        if self.columns {
+            self.format = Some(COLUMNS.parse().unwrap());
+        }
+        if let Some(path) = &self.tee {
+            let file = File::options().create(true).append(true).open(path);
+            self.tee_file = Some(file.unwrap());
+        }
+        if let Some(path) = self.out_file.clone() {
+            let out = Rotating::open(path, self.rotate_size, self.rotate_interval, self.gzip);
+            self.out = Some(RefCell::new(out));
+        }
+        // Partitions are named by producers, so they are kept out of the working directory.
+        assert!(
+            self.partition_by.is_none() || self.out_dir.is_some() || self.partition_dir.is_some(),
+            "--partition-by needs --partition-dir or --out-dir"
+        );
+        if let Some(dir) = &self.partition_dir {
+            fs::create_dir_all(dir).unwrap();
+        }
+        let file = if let Some(path) = &self.props_file {
+            File::create(path).unwrap()
+        } else if let Some(fd) = self.props_fd {
+            props_fd(fd)
+        } else {
+            return;
+        };
+        self.props = Some(RefCell::new(LineWriter::new(file)));
+    }
+
+    /// Writes the message from the queue out as a record on stdout and its metadata to the metadata stream.
+    pub fn write(&self, delivery: &Delivery, queue: &str) -> Result<(), Error> {
+        self.write_decompressed(delivery, queue, self.decompress(delivery)?)
+    }
+
+    /// Writes the message out like write, given its body as decompressed, which is owned only if it was compressed.
+    pub fn write_decompressed(
+        &self,
+        delivery: &Delivery,
+        queue: &str,
+        body: Cow<[u8]>,
+    ) -> Result<(), Error> {
+        let decompressed = matches!(body, Cow::Owned(_));
+        let partition = self.partition_by.as_ref().map(|by| by.key(delivery));
+        if let Some(dir) = &self.out_dir {
+            let dir = partition.map_or_else(|| dir.clone(), |partition| dir.join(partition));
+            archive(&dir, delivery);
+            return Ok(());
+        }
+        let bodies = self.bodies(body)?;
+        let records = bodies
+            .iter()
+            .map(|body| self.record(delivery, queue, body, decompressed))
+            .collect::<Result<Vec<_>, _>>()?;
+        if let Some(props) = &self.props {
+            writeln!(props.borrow_mut(), "{}", metadata(delivery)).unwrap();
+        }
+        for record in records {
+            self.emit(partition.as_deref(), &record)
+                .map_err(Error::Write)?;
+        }
+        Ok(())
+    }
+
+    /// Flushes stdout and the metadata stream, reporting whether everything written so far reached stdout.
+    pub fn flush(&self) -> Result<(), Error> {
+        if let Some(props) = &self.props {
+            props.borrow_mut().flush().unwrap();
+        }
+        if let Some(buffered) = self.stdout.borrow_mut().as_mut() {
+            buffered.flush().map_err(Error::Write)?;
+        }
+        Ok(())
+    }
+
+    /// How often stdout is flushed, if records are flushed at an interval rather than by count.
+    pub fn flush_interval(&self) -> Option<Duration> {
+        match self.flush_policy {
+            Flush::Interval(interval) if !interval.is_zero() => Some(interval),
+            _ => None,
+        }
+    }
+
+    /// Flushes stdout if records written to it have waited out the flush interval.
+    pub fn flush_due(&self) -> Result<(), Error> {
+        if let (Some(interval), Some(buffered)) =
+            (self.flush_interval(), self.stdout.borrow_mut().as_mut())
+        {
+            if buffered.records > 0 && buffered.flushed.elapsed() >= interval {
+                buffered.flush().map_err(Error::Write)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Decompresses the body according to its content encoding, unless asked not to.
+    pub fn decompress<'a>(&self, delivery: &'a Delivery) -> Result<Cow<'a, [u8]>, Error> {
+        let encoding = delivery.properties.content_encoding().as_ref();
+        Ok(match encoding.filter(|_| !self.no_decompress) {
+            Some(encoding) => match decompress(encoding.as_str(), &delivery.data) {
+                Some(body) => Cow::Owned(body.map_err(Error::Decompress)?),
+                None => Cow::Borrowed(&delivery.data[..]),
+            },
+            None => Cow::Borrowed(&delivery.data[..]),
+        })
+    }
+
+    /// Transforms the decompressed body into those to be written out.
+    fn bodies<'a>(&self, body: Cow<'a, [u8]>) -> Result<Vec<Cow<'a, [u8]>>, Error> {
+        let body = match self.decode.decode(&body) {
+            Some(decoded) => Cow::Owned(decoded.map_err(Error::Decode)?),
+            None => body,
+        };
+        let bodies = match &self.jq {
+            Some(jq) => {
+                let outputs = jq.run(&body).map_err(Error::Jq)?;
+                outputs
+                    .into_iter()
+                    .map(|output| Cow::Owned(output.into_bytes()))
+                    .collect()
+            }
+            None => vec![body],
+        };
+        if !self.pretty {
+            return Ok(bodies);
+        }
+        Ok(bodies
+            .into_iter()
+            .map(|body| pretty(&body, self.sort_keys).map_or(body, Cow::Owned))
+            .collect())
+    }
+
+    /// Writes the record to the tee file and to the partition, output file or stdout.
+    fn emit(&self, partition: Option<&str>, record: &[u8]) -> io::Result<()> {
+        if let Some(file) = &self.tee_file {
+            let mut out = BufWriter::new(file);
+            self.frame(&mut out, record).unwrap();
+            out.flush().unwrap();
+        }
+        if let (Some(partition), Some(dir)) = (partition, &self.partition_dir) {
+            let mut partitions = self.partitions.borrow_mut();
+            let file = partitions
+                .entry(partition.into())
+                .or_insert_with_key(|partition| {
+                    File::options()
+                        .create(true)
+                        .append(true)
+                        .open(dir.join(partition))
+                        .unwrap()
+                });
+            let mut out = BufWriter::new(file);
+            self.frame(&mut out, record).unwrap();
+            out.flush().unwrap();
+        } else if let Some(out) = &self.out {
+            let len = match self.framing {
+                Framing::Delimited => record.len() + 1,
+                Framing::LengthPrefix => record.len() + 4,
+            };
+            out.borrow_mut()
+                .write(len, |out| self.frame(out, record).unwrap());
+        } else {
+            let mut buffered = self.stdout.borrow_mut();
+            let buffered = buffered.get_or_insert_with(Buffered::default);
+            self.frame(&mut buffered.out, record)?;
+            return buffered.written(self.flush_policy);
+        }
+        Ok(())
+    }
+
+    /// Writes the record out with its delimiter or length prefix.
+    fn frame(&self, out: &mut impl Write, record: &[u8]) -> io::Result<()> {
+        match self.framing {
+            Framing::Delimited => {
+                out.write_all(record)?;
+                out.write_all(&[self.delimiter.byte()])
+            }
+            Framing::LengthPrefix => {
+                let len = u32::try_from(record.len()).unwrap();
+                out.write_all(&len.to_be_bytes())?;
+                out.write_all(record)
+            }
+        }
+    }
+
+    /// Renders the message as a record, leaving the content encoding out of json once the body has been decompressed
+    /// so that publishing the record again does not label the plain body as compressed.
+    fn record<'a>(
+        &self,
+        delivery: &Delivery,
+        queue: &str,
+        body: &'a [u8],
+        decompressed: bool,
+    ) -> Result<Cow<'a, [u8]>, Error> {
+        if self.output == Some(Format::Json) {
+            let mut envelope = envelope(delivery, Some(queue), body);
+            if decompressed {
+                envelope["properties"]
+                    .as_object_mut()
+                    .unwrap()
+                    .remove("content_encoding");
+            }
+            return Ok(envelope.to_string().into_bytes().into());
+        }
+        let raw = self.framing == Framing::LengthPrefix && self.encode == Encoding::Utf8;
+        if raw && self.format.is_none() {
+            return Ok(Cow::Borrowed(body));
+        }
+        let mut data = self.encode.encode(body)?;
+        if self.escape && self.encode == Encoding::Utf8 {
+            data = escape(&data).into();
+        }
+        let checked = !raw && !self.pretty && self.encode == Encoding::Utf8;
+        if checked && data.as_bytes().contains(&self.delimiter.byte()) {
+            return Err(Error::Delimiter(data.into()));
+        }
+        Ok(match &self.format {
+            Some(template) => template.render(delivery, queue, &data).into_bytes().into(),
+            None => match data {
+                Cow::Borrowed(data) => Cow::Borrowed(data.as_bytes()),
+                Cow::Owned(data) => Cow::Owned(data.into_bytes()),
+            },
+        })
+    }
+}
+
+/// A line template with placeholders for the body, delivery attributes, properties and headers.
+pub struct Template(Vec<Segment>);
+
+/// Part of a template.
+enum Segment {
+    /// Text copied as is.
+    Literal(String),
+
+    /// The message body.
+    Body,
+
+    /// The queue the message was consumed from.
+    Queue,
+
+    /// A delivery attribute or message property.
+    Field(String),
+
+    /// A message header.
+    Header(String),
+}
+
+/// Delivery attributes which can be looked up by name.
+const ATTRIBUTES: &[&str] = &[
+    "exchange",
+    "routing_key",
+    "delivery_tag",
+    "redelivered",
+    "size",
+];
+
+/// Template for --columns.
+const COLUMNS: &str = "{exchange}\t{routing_key}\t{delivery_tag}\t{redelivered}\t{size}\t{body}";
+
+/// Message properties which can be looked up by name.
+const PROPERTIES: &[&str] = &[
+    "content_type",
+    "content_encoding",
+    "delivery_mode",
+    "priority",
+    "correlation_id",
+    "reply_to",
+    "expiration",
+    "message_id",
+    "timestamp",
+    "type",
+    "user_id",
+    "app_id",
+    "cluster_id",
+];
+
+impl FromStr for Template {
+    type Err = String;
+
+    fn from_str(template: &str) -> Result<Self, Self::Err> {
+        let (mut segments, mut literal) = (vec![], String::new());
+        let mut chars = template.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some('t') => literal.push('\t'),
+                    Some('n') => literal.push('\n'),
+                    Some('r') => literal.push('\r'),
+                    Some('0') => literal.push('\0'),
+                    Some(c) => literal.push(c),
+                    None => literal.push('\\'),
+                },
+                '{' => {
+                    let name: String = chars.by_ref().take_while(|&c| c != '}').collect();
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    segments.push(if name == "body" {
+                        Segment::Body
+                    } else if name == "queue" {
+                        Segment::Queue
+                    } else if let Some(header) = name.strip_prefix("headers.") {
+                        Segment::Header(header.into())
+                    } else if ATTRIBUTES.contains(&name.as_str())
+                        || PROPERTIES.contains(&name.as_str())
+                    {
+                        Segment::Field(name)
+                    } else {
+                        return Err(format!("unknown placeholder: {{{name}}}"));
+                    });
+                }
+                _ => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+        Ok(Self(segments))
+    }
+}
+
+impl Template {
+    /// Fills in the placeholders, leaving absent properties and headers empty.
+    fn render(&self, delivery: &Delivery, queue: &str, body: &str) -> String {
+        let mut line = String::new();
+        for segment in &self.0 {
+            match segment {
+                Segment::Literal(literal) => line.push_str(literal),
+                Segment::Body => line.push_str(body),
+                Segment::Queue => line.push_str(queue),
+                Segment::Field(name) => line.push_str(&field(delivery, name).unwrap_or_default()),
+                Segment::Header(name) => line.push_str(&header(delivery, name).unwrap_or_default()),
+            }
+        }
+        line
+    }
+}
+
+/// Looks up a delivery attribute or message property by name.
+fn field(delivery: &Delivery, name: &str) -> Option<String> {
+    fn string<T: ToString>(value: Option<&T>) -> Option<String> {
+        value.map(ToString::to_string)
+    }
+    let props = &delivery.properties;
+    match name {
+        "exchange" => Some(delivery.exchange.to_string()),
+        "routing_key" => Some(delivery.routing_key.to_string()),
+        "delivery_tag" => Some(delivery.delivery_tag.to_string()),
+        "redelivered" => Some(delivery.redelivered.to_string()),
+        "size" => Some(delivery.data.len().to_string()),
+        "content_type" => string(props.content_type().as_ref()),
+        "content_encoding" => string(props.content_encoding().as_ref()),
+        "delivery_mode" => string(props.delivery_mode().as_ref()),
+        "priority" => string(props.priority().as_ref()),
+        "correlation_id" => string(props.correlation_id().as_ref()),
+        "reply_to" => string(props.reply_to().as_ref()),
+        "expiration" => string(props.expiration().as_ref()),
+        "message_id" => string(props.message_id().as_ref()),
+        "timestamp" => string(props.timestamp().as_ref()),
+        "type" => string(props.kind().as_ref()),
+        "user_id" => string(props.user_id().as_ref()),
+        "app_id" => string(props.app_id().as_ref()),
+        "cluster_id" => string(props.cluster_id().as_ref()),
+        _ => None,
+    }
+}
+
+/// Writes the body to its own file in the directory, with its metadata in a json file next to it.
+fn archive(dir: &Path, delivery: &Delivery) {
+    let name = delivery
+        .properties
+        .message_id()
+        .as_ref()
+        .and_then(|id| file_name(id.as_str()))
+        .unwrap_or_else(|| delivery.delivery_tag.to_string());
+    fs::create_dir_all(dir).unwrap();
+    let (mut body, mut sidecar) = create_unique(dir, &name);
+    body.write_all(&delivery.data).unwrap();
+    sidecar
+        .write_all(metadata(delivery).to_string().as_bytes())
+        .unwrap();
+}
+
+/// Creates the body file with the name, along with its .json sidecar, or with a numeric suffix once either is taken,
+/// so earlier messages are never overwritten.
+fn create_unique(dir: &Path, name: &str) -> (File, File) {
+    let mut suffix = 0;
+    loop {
+        let candidate = match suffix {
+            0 => name.to_owned(),
+            suffix => format!("{name}-{suffix}"),
+        };
+        let path = dir.join(&candidate);
+        if let Some(body) = create_new(&path) {
+            if let Some(sidecar) = create_new(&dir.join(format!("{candidate}.json"))) {
+                return (body, sidecar);
+            }
+            fs::remove_file(&path).unwrap();
+        }
+        suffix += 1;
+    }
+}
+
+/// Creates the file unless it already exists.
+fn create_new(path: &Path) -> Option<File> {
+    match OpenOptions::new().write(true).create_new(true).open(path) {
+        Ok(file) => Some(file),
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => None,
+        Err(err) => panic!("cannot create {}: {err}", path.display()),
+    }
+}
+
+/// Replaces characters which are unsafe in file names, unless nothing usable is left.
+fn file_name(name: &str) -> Option<String> {
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || "-_.".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    (!name.trim_matches('.').is_empty()).then_some(name)
+}
+
+/// What output is partitioned by.
+enum Partition {
+    /// The routing key the message was published with.
+    RoutingKey,
+
+    /// The value of a message header.
+    Header(String),
+}
+
+impl FromStr for Partition {
+    type Err = String;
+
+    fn from_str(partition: &str) -> Result<Self, Self::Err> {
+        Ok(match partition {
+            "routing-key" => Self::RoutingKey,
+            header => Self::Header(header.into()),
+        })
+    }
+}
+
+impl Partition {
+    /// Names the file or directory the message belongs in, using _ when there is no usable value.
+    fn key(&self, delivery: &Delivery) -> String {
+        let key = match self {
+            Self::RoutingKey => Some(delivery.routing_key.to_string()),
+            Self::Header(name) => header(delivery, name),
+        };
+        key.as_deref()
+            .and_then(file_name)
+            .unwrap_or_else(|| "_".into())
+    }
+}
+
+/// Takes ownership of an inherited file descriptor.
+#[cfg(target_family = "unix")]
+fn props_fd(fd: i32) -> File {
+    use std::os::unix::io::FromRawFd;
+    // SAFETY: the descriptor was passed in by the caller for this program to write to.
+    unsafe { File::from_raw_fd(fd) }
+}
+
+/// File descriptors cannot be passed in on this platform.
+#[cfg(not(target_family = "unix"))]
+fn props_fd(_fd: i32) -> File {
+    panic!("--props-fd is only supported on unix")
+}
+
+/// Represents the message and its metadata as a json object, base64 encoding binary bodies.
+pub fn envelope(delivery: &Delivery, queue: Option<&str>, body: &[u8]) -> serde_json::Value {
+    let mut envelope = metadata(delivery);
+    if let Some(queue) = queue {
+        envelope["queue"] = queue.into();
+    }
+    if let Ok(body) = std::str::from_utf8(body) {
+        envelope["body"] = body.into();
+    } else {
+        envelope["body"] = STANDARD.encode(body).into();
+        envelope["body_encoding"] = "base64".into();
+    }
+    envelope
+}
+
+/// Represents the delivery attributes, properties and headers as a json object.
+pub fn metadata(delivery: &Delivery) -> serde_json::Value {
+    let props = &delivery.properties;
+    let mut properties = serde_json::Map::new();
+    for name in PROPERTIES {
+        let value = match *name {
+            "delivery_mode" => props.delivery_mode().map(Into::into),
+            "priority" => props.priority().map(Into::into),
+            "timestamp" => props.timestamp().map(Into::into),
+            _ => field(delivery, name).map(Into::into),
+        };
+        if let Some(value) = value {
+            properties.insert((*name).into(), value);
+        }
+    }
+    let headers = props.headers().as_ref().map_or_else(
+        || serde_json::Value::Object(serde_json::Map::new()),
+        |headers| json_value(&AMQPValue::FieldTable(headers.clone())),
+    );
+    serde_json::json!({
+        "exchange": delivery.exchange.as_str(),
+        "routing_key": delivery.routing_key.as_str(),
+        "delivery_tag": delivery.delivery_tag,
+        "redelivered": delivery.redelivered,
+        "properties": properties,
+        "headers": headers,
+    })
+}
+
+/// Looks up a message header by name, rendering it as text.
+pub fn header(delivery: &Delivery, name: &str) -> Option<String> {
+    let value = delivery.properties.headers().as_ref()?.inner().get(name)?;
+    Some(match json_value(value) {
+        serde_json::Value::String(value) => value,
+        value => value.to_string(),
+    })
+}
+
+/// Converts an amqp value into its closest json representation.
+pub fn json_value(value: &AMQPValue) -> serde_json::Value {
+    use serde_json::Value;
+    match value {
+        AMQPValue::Boolean(value) => Value::Bool(*value),
+        AMQPValue::ShortShortInt(value) => (*value).into(),
+        AMQPValue::ShortShortUInt(value) => (*value).into(),
+        AMQPValue::ShortInt(value) => (*value).into(),
+        AMQPValue::ShortUInt(value) => (*value).into(),
+        AMQPValue::LongInt(value) => (*value).into(),
+        AMQPValue::LongUInt(value) => (*value).into(),
+        AMQPValue::LongLongInt(value) => (*value).into(),
+        AMQPValue::Timestamp(value) => (*value).into(),
+        AMQPValue::Float(value) => (*value).into(),
+        AMQPValue::Double(value) => (*value).into(),
+        AMQPValue::DecimalValue(value) => {
+            (f64::from(value.value) / 10_f64.powi(value.scale.into())).into()
+        }
+        AMQPValue::ShortString(value) => value.as_str().into(),
+        AMQPValue::LongString(value) => value.to_string().into(),
+        AMQPValue::ByteArray(value) => String::from_utf8_lossy(value.as_slice()).into(),
+        AMQPValue::FieldArray(values) => values.as_slice().iter().map(json_value).collect(),
+        AMQPValue::FieldTable(values) => Value::Object(
+            values
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), json_value(value)))
+                .collect(),
+        ),
+        AMQPValue::Void => Value::Null,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{create_unique, escape, hex, unescape, xxd, Flush, Template};
+    use amq_protocol_types::{AMQPValue, FieldTable};
+    use core::time::Duration;
+    use lapin::{acker::Acker, message::Delivery, BasicProperties};
+    use std::fs;
+
+    fn delivery() -> Delivery {
+        let mut headers = FieldTable::default();
+        headers.insert("region".into(), AMQPValue::LongString("eu".into()));
+        Delivery {
+            delivery_tag: 7,
+            exchange: "orders".into(),
+            routing_key: "order.created".into(),
+            redelivered: false,
+            properties: BasicProperties::default()
+                .with_message_id("m1".into())
+                .with_headers(headers),
+            data: b"{}".to_vec(),
+            acker: Acker::default(),
+        }
+    }
+
+    fn render(template: &str) -> String {
+        let template: Template = template.parse().unwrap();
+        template.render(&delivery(), "orders.q", "{}")
+    }
+
+    #[test]
+    fn renders_placeholders() {
+        assert_eq!(
+            render("{queue} {exchange}/{routing_key} #{delivery_tag} {message_id}: {body}"),
+            "orders.q orders/order.created #7 m1: {}"
+        );
+        assert_eq!(
+            render("{headers.region}|{headers.missing}|{priority}"),
+            "eu||"
+        );
+    }
+
+    #[test]
+    fn unescapes_literals() {
+        assert_eq!(render("{routing_key}\\t{size}\\n"), "order.created\t2\n");
+        assert_eq!(render("a\\\\b\\"), "a\\b\\");
+    }
+
+    #[test]
+    fn rejects_unknown_placeholders() {
+        assert!("{nope}".parse::<Template>().is_err());
+        assert!("{body} {headers}".parse::<Template>().is_err());
+    }
+
+    #[test]
+    fn archived_names_never_collide_with_sidecars() {
+        let dir = std::env::temp_dir().join(format!("amqpcli-archive-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        drop(create_unique(&dir, "a.json"));
+        drop(create_unique(&dir, "a"));
+        drop(create_unique(&dir, "a"));
+        let mut names: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            names,
+            [
+                "a-1",
+                "a-1.json",
+                "a-2",
+                "a-2.json",
+                "a.json",
+                "a.json.json"
+            ]
+        );
+    }
+
+    #[test]
+    fn dumps_like_xxd() {
+        let dump = xxd(b"hello, world!\x00\x01\xffabc");
+        assert_eq!(
+            dump,
+            "00000000: 6865 6c6c 6f2c 2077 6f72 6c64 2100 01ff  hello, world!...\n\
+             00000010: 6162 63                                  abc"
+        );
+        assert_eq!(xxd(b""), "");
+    }
+
+    #[test]
+    fn encodes_hex() {
+        assert_eq!(hex(b"\x00\x7f\xff"), "007fff");
+    }
+
+    #[test]
+    fn escapes_delimiters() {
+        assert_eq!(escape("a\nb\r\0c\\n"), "a\\nb\\r\\0c\\\\n");
+        assert!(!escape("line\nbreak").contains('\n'));
+    }
+
+    #[test]
+    fn unescape_reverses_escape() {
+        for data in [
+            "",
+            "plain",
+            "a\nb",
+            "\\n is not a newline",
+            "\r\n\0\\",
+            "trailing\\",
+        ] {
+            assert_eq!(unescape(&escape(data)), data);
+        }
+        assert_eq!(unescape("\\q\\"), "q\\");
+    }
+
+    #[test]
+    fn parses_flush_policies() {
+        assert!(matches!("every-message".parse(), Ok(Flush::Message)));
+        assert!(matches!("every-100".parse(), Ok(Flush::Records(100))));
+        assert!(matches!(
+            "every-50ms".parse(),
+            Ok(Flush::Interval(interval)) if interval == Duration::from_millis(50)
+        ));
+        for invalid in [
+            "message",
+            "every-0",
+            "every-",
+            "every-10s",
+            "every--1",
+            "every-xms",
+        ] {
+            assert!(invalid.parse::<Flush>().is_err(), "{invalid}");
+        }
+    }
+}
@@ -0,0 +1,149 @@
+//! Output files which are rotated once they get too big or too old.
+use core::time::Duration;
+use flate2::{write::GzEncoder, Compression};
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// A file which is moved aside and replaced by a fresh one according to its limits.
+pub struct Rotating {
+    /// Where records are currently written.
+    path: PathBuf,
+
+    /// The open file.
+    file: File,
+
+    /// Number of bytes in the current file.
+    size: u64,
+
+    /// When the current file was opened.
+    opened: Instant,
+
+    /// Rotates before the file would grow beyond this many bytes.
+    max_size: Option<u64>,
+
+    /// Rotates once the file has been open for this long.
+    max_age: Option<Duration>,
+
+    /// Whether to gzip rotated files.
+    gzip: bool,
+}
+
+impl Rotating {
+    /// Opens the file for appending.
+    pub fn open(
+        path: PathBuf,
+        max_size: Option<u64>,
+        max_age: Option<Duration>,
+        gzip: bool,
+    ) -> Self {
+        let file = append(&path);
+        Self {
+            size: file.metadata().unwrap().len(),
+            path,
+            file,
+            opened: Instant::now(),
+            max_size,
+            max_age,
+            gzip,
+        }
+    }
+
+    /// Writes a record with the given function, rotating first if it would not fit.
+    pub fn write(&mut self, len: usize, write: impl FnOnce(&mut BufWriter<&File>)) {
+        let len = len as u64;
+        let full = self.max_size.is_some_and(|max| self.size + len > max);
+        let old = self.max_age.is_some_and(|max| self.opened.elapsed() >= max);
+        if self.size > 0 && (full || old) {
+            self.rotate();
+        }
+        let mut out = BufWriter::new(&self.file);
+        write(&mut out);
+        out.flush().unwrap();
+        self.size += len;
+    }
+
+    /// Moves the current file aside with a timestamp suffix and starts a new one, adding a sequence number when
+    /// another file was rotated within the same millisecond.
+    fn rotate(&mut self) {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        let mut suffix = 0;
+        let rotated = loop {
+            let mut rotated = self.path.clone().into_os_string();
+            match suffix {
+                0 => rotated.push(format!(".{millis}")),
+                suffix => rotated.push(format!(".{millis}-{suffix}")),
+            }
+            let rotated = PathBuf::from(rotated);
+            if !rotated.exists() && !gzipped(&rotated).exists() {
+                break rotated;
+            }
+            suffix += 1;
+        };
+        fs::rename(&self.path, &rotated).unwrap();
+        self.file = append(&self.path);
+        self.size = 0;
+        self.opened = Instant::now();
+        if self.gzip {
+            compress(rotated);
+        }
+    }
+}
+
+/// Opens a file for appending, creating it if necessary.
+fn append(path: &Path) -> File {
+    File::options()
+        .create(true)
+        .append(true)
+        .open(path)
+        .unwrap()
+}
+
+/// Where the gzipped copy of the file goes.
+fn gzipped(path: &Path) -> PathBuf {
+    let mut gz = path.as_os_str().to_owned();
+    gz.push(".gz");
+    PathBuf::from(gz)
+}
+
+/// Replaces the file with a gzipped copy.
+fn compress(path: PathBuf) {
+    let mut encoder = GzEncoder::new(
+        File::create(gzipped(&path)).unwrap(),
+        Compression::default(),
+    );
+    io::copy(&mut File::open(&path).unwrap(), &mut encoder).unwrap();
+    encoder.finish().unwrap();
+    fs::remove_file(path).unwrap();
+}
+
+/// Parses a number of bytes with an optional K, M or G suffix, e.g. 100M.
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let (digits, shift) = match size.chars().last() {
+        Some('K' | 'k') => (&size[..size.len() - 1], 10),
+        Some('M' | 'm') => (&size[..size.len() - 1], 20),
+        Some('G' | 'g') => (&size[..size.len() - 1], 30),
+        _ => (size, 0),
+    };
+    let size: u64 = digits.parse::<u64>().map_err(|err| err.to_string())?;
+    Ok(size << shift)
+}
+
+/// Parses a duration with an s, m, h or d suffix, e.g. 1h, defaulting to seconds.
+pub fn parse_duration(duration: &str) -> Result<Duration, String> {
+    let (digits, unit) = match duration.chars().last() {
+        Some('s') => (&duration[..duration.len() - 1], 1),
+        Some('m') => (&duration[..duration.len() - 1], 60),
+        Some('h') => (&duration[..duration.len() - 1], 60 * 60),
+        Some('d') => (&duration[..duration.len() - 1], 24 * 60 * 60),
+        _ => (duration, 1),
+    };
+    let duration: u64 = digits.parse::<u64>().map_err(|err| err.to_string())?;
+    Ok(Duration::from_secs(duration * unit))
+}
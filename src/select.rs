@@ -0,0 +1,331 @@
+//! Selector expressions for filtering messages by their metadata, in the style of jms selectors.
+use serde_json::Value;
+use std::{cmp::Ordering, iter::Peekable, str::FromStr, vec::IntoIter};
+
+/// A parsed expression such as `headers.region = 'eu' AND properties.priority > 3`.
+pub struct Selector(Expr);
+
+/// A boolean expression.
+enum Expr {
+    /// Both sides hold.
+    And(Box<Expr>, Box<Expr>),
+
+    /// Either side holds.
+    Or(Box<Expr>, Box<Expr>),
+
+    /// The expression does not hold.
+    Not(Box<Expr>),
+
+    /// Compares two operands.
+    Compare(Operand, Ordering, bool, Operand),
+
+    /// Checks whether the operand is absent, or present if negated.
+    IsNull(Operand, bool),
+}
+
+/// A value being compared.
+enum Operand {
+    /// A path into the message metadata, e.g. `headers.region` or `routing_key`.
+    Field(Vec<String>),
+
+    /// A string, number or boolean literal.
+    Literal(Value),
+}
+
+/// A lexical token.
+#[derive(PartialEq)]
+enum Token {
+    /// A field name or keyword.
+    Ident(String),
+
+    /// A quoted string.
+    Str(String),
+
+    /// A number.
+    Num(f64),
+
+    /// A comparison operator.
+    Op(&'static str),
+
+    /// An opening parenthesis.
+    Open,
+
+    /// A closing parenthesis.
+    Close,
+}
+
+impl FromStr for Selector {
+    type Err = String;
+
+    fn from_str(selector: &str) -> Result<Self, Self::Err> {
+        let mut tokens = tokenize(selector)?.into_iter().peekable();
+        let expr = or(&mut tokens)?;
+        if tokens.next().is_some() {
+            return Err(format!("unexpected trailing input in selector: {selector}"));
+        }
+        Ok(Self(expr))
+    }
+}
+
+impl Selector {
+    /// Whether the message metadata satisfies the expression, absent fields never do.
+    pub fn matches(&self, metadata: &Value) -> bool {
+        self.0.eval(metadata) == Some(true)
+    }
+}
+
+impl Expr {
+    /// Evaluates the expression, giving none when it depends on an absent field.
+    fn eval(&self, metadata: &Value) -> Option<bool> {
+        match self {
+            Self::And(left, right) => match (left.eval(metadata), right.eval(metadata)) {
+                (Some(false), _) | (_, Some(false)) => Some(false),
+                (Some(true), Some(true)) => Some(true),
+                _ => None,
+            },
+            Self::Or(left, right) => match (left.eval(metadata), right.eval(metadata)) {
+                (Some(true), _) | (_, Some(true)) => Some(true),
+                (Some(false), Some(false)) => Some(false),
+                _ => None,
+            },
+            Self::Not(expr) => expr.eval(metadata).map(|value| !value),
+            Self::Compare(left, ordering, negated, right) => {
+                let left = left.value(metadata)?;
+                let right = right.value(metadata)?;
+                let actual = match (left, right) {
+                    (Value::Number(left), Value::Number(right)) => {
+                        left.as_f64()?.partial_cmp(&right.as_f64()?)?
+                    }
+                    (Value::String(left), Value::String(right)) => left.cmp(right),
+                    (Value::Bool(left), Value::Bool(right)) => left.cmp(right),
+                    _ => return None,
+                };
+                Some((actual == *ordering) != *negated)
+            }
+            Self::IsNull(operand, negated) => Some(operand.value(metadata).is_none() != *negated),
+        }
+    }
+}
+
+impl Operand {
+    /// Looks up the value, giving none for absent or null fields.
+    fn value<'a>(&'a self, metadata: &'a Value) -> Option<&'a Value> {
+        match self {
+            Self::Field(path) => path
+                .iter()
+                .try_fold(metadata, |value, name| value.get(name))
+                .filter(|value| !value.is_null()),
+            Self::Literal(value) => Some(value),
+        }
+    }
+}
+
+/// Splits the selector into tokens.
+fn tokenize(selector: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut chars = selector.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' | ')' => {
+                chars.next();
+                tokens.push(if c == '(' { Token::Open } else { Token::Close });
+            }
+            '\'' => {
+                chars.next();
+                let mut string = String::new();
+                loop {
+                    match chars.next() {
+                        Some('\'') if chars.peek() == Some(&'\'') => {
+                            chars.next();
+                            string.push('\'');
+                        }
+                        Some('\'') => break,
+                        Some(c) => string.push(c),
+                        None => return Err(format!("unterminated string in selector: {selector}")),
+                    }
+                }
+                tokens.push(Token::Str(string));
+            }
+            '=' | '<' | '>' | '!' => {
+                chars.next();
+                let next = chars.peek().copied();
+                let op = match (c, next) {
+                    ('<', Some('=')) => "<=",
+                    ('>', Some('=')) => ">=",
+                    ('<', Some('>')) | ('!', Some('=')) => "<>",
+                    ('=', _) => "=",
+                    ('<', _) => "<",
+                    ('>', _) => ">",
+                    _ => return Err(format!("unknown operator in selector: {selector}")),
+                };
+                if op.len() == 2 {
+                    chars.next();
+                }
+                tokens.push(Token::Op(op));
+            }
+            c if c.is_ascii_digit() || c == '-' => {
+                let mut number = String::new();
+                while let Some(&c) = chars
+                    .peek()
+                    .filter(|&&c| c.is_ascii_digit() || ".-eE".contains(c))
+                {
+                    number.push(c);
+                    chars.next();
+                }
+                let number = number
+                    .parse()
+                    .map_err(|_| format!("invalid number in selector: {number}"))?;
+                tokens.push(Token::Num(number));
+            }
+            c if c.is_alphanumeric() || c == '_' => {
+                let mut ident = String::new();
+                while let Some(&c) = chars
+                    .peek()
+                    .filter(|&&c| c.is_alphanumeric() || "_.-".contains(c))
+                {
+                    ident.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Ident(ident));
+            }
+            c => return Err(format!("unexpected character in selector: {c}")),
+        }
+    }
+    Ok(tokens)
+}
+
+/// The tokens still to be parsed.
+type Tokens = Peekable<IntoIter<Token>>;
+
+/// Consumes the keyword if it comes next.
+fn keyword(tokens: &mut Tokens, keyword: &str) -> bool {
+    let next = tokens.next_if(
+        |token| matches!(token, Token::Ident(ident) if ident.eq_ignore_ascii_case(keyword)),
+    );
+    next.is_some()
+}
+
+/// Parses expressions separated by `OR`.
+fn or(tokens: &mut Tokens) -> Result<Expr, String> {
+    let mut expr = and(tokens)?;
+    while keyword(tokens, "or") {
+        expr = Expr::Or(Box::new(expr), Box::new(and(tokens)?));
+    }
+    Ok(expr)
+}
+
+/// Parses expressions separated by `AND`.
+fn and(tokens: &mut Tokens) -> Result<Expr, String> {
+    let mut expr = not(tokens)?;
+    while keyword(tokens, "and") {
+        expr = Expr::And(Box::new(expr), Box::new(not(tokens)?));
+    }
+    Ok(expr)
+}
+
+/// Parses an optionally negated comparison or parenthesised expression.
+fn not(tokens: &mut Tokens) -> Result<Expr, String> {
+    if keyword(tokens, "not") {
+        return Ok(Expr::Not(Box::new(not(tokens)?)));
+    }
+    if tokens.next_if_eq(&Token::Open).is_some() {
+        let expr = or(tokens)?;
+        return match tokens.next() {
+            Some(Token::Close) => Ok(expr),
+            _ => Err("expected ) in selector".into()),
+        };
+    }
+    let left = operand(tokens)?;
+    if keyword(tokens, "is") {
+        let negated = keyword(tokens, "not");
+        if !keyword(tokens, "null") {
+            return Err("expected NULL after IS in selector".into());
+        }
+        return Ok(Expr::IsNull(left, negated));
+    }
+    let (ordering, negated) = match tokens.next() {
+        Some(Token::Op("=")) => (Ordering::Equal, false),
+        Some(Token::Op("<>")) => (Ordering::Equal, true),
+        Some(Token::Op("<")) => (Ordering::Less, false),
+        Some(Token::Op(">=")) => (Ordering::Less, true),
+        Some(Token::Op(">")) => (Ordering::Greater, false),
+        Some(Token::Op("<=")) => (Ordering::Greater, true),
+        _ => return Err("expected a comparison in selector".into()),
+    };
+    Ok(Expr::Compare(left, ordering, negated, operand(tokens)?))
+}
+
+/// Parses a field or literal.
+fn operand(tokens: &mut Tokens) -> Result<Operand, String> {
+    Ok(match tokens.next() {
+        Some(Token::Str(string)) => Operand::Literal(string.into()),
+        Some(Token::Num(number)) => Operand::Literal(number.into()),
+        Some(Token::Ident(ident)) if ident.eq_ignore_ascii_case("true") => {
+            Operand::Literal(true.into())
+        }
+        Some(Token::Ident(ident)) if ident.eq_ignore_ascii_case("false") => {
+            Operand::Literal(false.into())
+        }
+        Some(Token::Ident(ident)) => Operand::Field(ident.split('.').map(Into::into).collect()),
+        _ => return Err("expected a field or value in selector".into()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Selector;
+    use serde_json::{json, Value};
+
+    fn matches(selector: &str, metadata: &Value) -> bool {
+        selector.parse::<Selector>().unwrap().matches(metadata)
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let metadata = json!({"a": 1, "b": 0, "c": 0});
+        assert!(matches("a = 1 OR b = 1 AND c = 1", &metadata));
+        assert!(!matches("(a = 1 OR b = 1) AND c = 1", &metadata));
+    }
+
+    #[test]
+    fn not_binds_tighter_than_and() {
+        let metadata = json!({"a": 0, "b": 0});
+        assert!(!matches("NOT a = 1 AND b = 1", &metadata));
+        assert!(matches("NOT (a = 1 AND b = 1)", &metadata));
+    }
+
+    #[test]
+    fn absent_fields_are_unknown() {
+        let metadata = json!({"a": 1, "b": null});
+        assert!(!matches("missing = 1", &metadata));
+        assert!(!matches("NOT missing = 1", &metadata));
+        assert!(!matches("b = 1", &metadata));
+        assert!(!matches("missing = 1 AND a = 1", &metadata));
+        assert!(matches("NOT (missing = 1 AND a = 2)", &metadata));
+        assert!(matches("missing = 1 OR a = 1", &metadata));
+        assert!(!matches("missing = 1 OR a = 2", &metadata));
+        assert!(matches("missing IS NULL AND b IS NULL", &metadata));
+        assert!(matches("a IS NOT NULL", &metadata));
+    }
+
+    #[test]
+    fn not_equal_operators() {
+        let metadata = json!({"headers": {"region": "eu"}, "priority": 2});
+        for op in ["<>", "!="] {
+            assert!(matches(&format!("priority {op} 1"), &metadata));
+            assert!(!matches(&format!("priority {op} 2"), &metadata));
+            assert!(matches(&format!("headers.region {op} 'us'"), &metadata));
+            assert!(!matches(&format!("missing {op} 1"), &metadata));
+        }
+    }
+
+    #[test]
+    fn mismatched_types_are_unknown() {
+        let metadata = json!({"priority": 2});
+        assert!(!matches("priority = '2'", &metadata));
+        assert!(!matches("priority <> '2'", &metadata));
+    }
+}
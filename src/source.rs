@@ -0,0 +1,444 @@
+//! Reading the records to publish, from stdin or files.
+use crate::output::{unescape, Delimiter, Framing};
+use core::time::Duration;
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File, Metadata},
+    io::{self, stdin, BufRead, BufReader, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use structopt::StructOpt;
+use tokio::{sync::mpsc, time::Instant};
+
+/// Options for where the records to publish are read from.
+#[derive(StructOpt)]
+pub struct SourceOpts {
+    /// Files to publish line by line instead of stdin.
+    #[structopt(name = "FILE")]
+    files: Vec<PathBuf>,
+
+    /// What terminates each record of input, either newline or nul, so records can contain newlines.
+    #[structopt(long, default_value = "newline", possible_values = &["newline", "nul"])]
+    delimiter: Delimiter,
+
+    /// What separates records instead of the delimiter, e.g. '\n---\n', so multi-line documents can be published as
+    /// single messages.
+    #[structopt(long, parse(try_from_str = parse_separator))]
+    record_separator: Option<String>,
+
+    /// Either delimited, or length-prefix for stdin and files holding raw bodies each preceded by their 4 byte big
+    /// endian length.
+    #[structopt(long, default_value = "delimited", possible_values = &["delimited", "length-prefix"])]
+    framing: Framing,
+
+    /// Whether to publish each file as a single message instead of line by line.
+    #[structopt(long, requires = "FILE")]
+    whole_files: bool,
+
+    /// Publishes each line appended to this file from now on, like tail -f, reopening it when it is rotated.
+    #[structopt(long, conflicts_with = "FILE")]
+    follow: Option<PathBuf>,
+
+    /// Publishes the content of each file in this directory as a message, including those already there, once they
+    /// stop growing, skipping hidden files so they can be written under a hidden name and then renamed.
+    #[structopt(long, conflicts_with_all = &["FILE", "follow"])]
+    watch: Option<PathBuf>,
+
+    /// Whether to delete watched files once they are published.
+    #[structopt(long, requires = "watch")]
+    delete_published: bool,
+
+    /// Moves watched files into this directory once they are published.
+    #[structopt(long, requires = "watch", conflicts_with = "delete-published")]
+    move_published: Option<PathBuf>,
+}
+
+/// A record to publish, along with the name of the file it came from, if any.
+pub struct Record {
+    /// The content of the record.
+    pub data: Vec<u8>,
+
+    /// Name of the file the record was read from, without its directory.
+    pub file: Option<Arc<str>>,
+
+    /// The watched file the record is the content of, to be finished with once published.
+    pub watched: Option<PathBuf>,
+}
+
+/// Records as they are read.
+pub type Records = mpsc::Receiver<io::Result<Record>>;
+
+impl SourceOpts {
+    /// Reads the records on a separate thread, so that waiting for them can be abandoned.
+    pub fn open(&self) -> Records {
+        let (sender, receiver) = mpsc::channel(1024);
+        let separator = match &self.record_separator {
+            Some(separator) => separator.clone().into_bytes(),
+            None => vec![self.delimiter.byte()],
+        };
+        let framing = self.framing;
+        if let Some(path) = self.follow.clone() {
+            std::thread::spawn(move || follow(&path, &separator, &sender));
+            return receiver;
+        }
+        if let Some(dir) = self.watch.clone() {
+            std::thread::spawn(move || watch(&dir, &sender));
+            return receiver;
+        }
+        let (files, whole_files) = (self.files.clone(), self.whole_files);
+        std::thread::spawn(move || {
+            if files.is_empty() {
+                let lines = split(stdin().lock(), &separator, framing);
+                send_all(
+                    &sender,
+                    lines.map(|line| {
+                        line.map(|data| Record {
+                            data,
+                            file: None,
+                            watched: None,
+                        })
+                    }),
+                );
+            }
+            for path in files {
+                let name = file_name(&path);
+                let record = |data| Record {
+                    data,
+                    file: Some(name.clone()),
+                    watched: None,
+                };
+                let sent = if whole_files {
+                    let data = fs::read(&path).map_err(|err| context(&path, &err));
+                    sender.blocking_send(data.map(record)).is_ok()
+                } else {
+                    match File::open(&path) {
+                        Ok(file) => {
+                            let lines = split(BufReader::new(file), &separator, framing);
+                            let lines = lines
+                                .map(|line| line.map(record).map_err(|err| context(&path, &err)));
+                            send_all(&sender, lines)
+                        }
+                        Err(err) => sender.blocking_send(Err(context(&path, &err))).is_ok(),
+                    }
+                };
+                if !sent {
+                    break;
+                }
+            }
+        });
+        receiver
+    }
+
+    /// Deletes or moves a watched file, if asked to, once it has been published.
+    pub fn published(&self, path: &Path) {
+        let result = match &self.move_published {
+            Some(dir) => fs::rename(path, dir.join(path.file_name().unwrap())),
+            None if self.delete_published => fs::remove_file(path),
+            None => return,
+        };
+        if let Err(err) = result {
+            eprintln!("cannot finish with {}: {err}", path.display());
+        }
+    }
+}
+
+/// Splits the input into records at the separator or by their length prefixes.
+fn split<'a>(
+    reader: impl BufRead + 'a,
+    separator: &'a [u8],
+    framing: Framing,
+) -> Box<dyn Iterator<Item = io::Result<Vec<u8>>> + 'a> {
+    match framing {
+        Framing::Delimited => Box::new(Separated { reader, separator }),
+        Framing::LengthPrefix => Box::new(LengthPrefixed(reader)),
+    }
+}
+
+/// Records followed by a separator, except perhaps the last.
+struct Separated<'a, R> {
+    /// Where the records are read from.
+    reader: R,
+
+    /// What follows each record.
+    separator: &'a [u8],
+}
+
+impl<R: BufRead> Iterator for Separated<'_, R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut record = vec![];
+        loop {
+            match read_record(&mut self.reader, self.separator, &mut record) {
+                Ok(0) if record.is_empty() => return None,
+                Ok(0) => return Some(Ok(record)),
+                Ok(_) if ends(&mut record, self.separator) => return Some(Ok(record)),
+                Ok(_) => {}
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+}
+
+/// Reads up to and including the next occurrence of the last byte of the separator, giving the number of bytes read.
+fn read_record(
+    reader: &mut impl BufRead,
+    separator: &[u8],
+    record: &mut Vec<u8>,
+) -> io::Result<usize> {
+    reader.read_until(*separator.last().unwrap(), record)
+}
+
+/// Whether the record ends with the separator, in which case it is removed along with the carriage return of windows
+/// line endings.
+fn ends(record: &mut Vec<u8>, separator: &[u8]) -> bool {
+    if !record.ends_with(separator) {
+        return false;
+    }
+    record.truncate(record.len() - separator.len());
+    if separator == b"\n" && record.last() == Some(&b'\r') {
+        record.pop();
+    }
+    true
+}
+
+/// Parses a record separator, turning backslash escapes into the characters they stand for.
+fn parse_separator(separator: &str) -> Result<String, String> {
+    match unescape(separator) {
+        separator if separator.is_empty() => Err("the record separator cannot be empty".into()),
+        separator => Ok(separator),
+    }
+}
+
+/// Records each preceded by their 4 byte big endian length, which is only allocated for as it is read.
+struct LengthPrefixed<R>(R);
+
+impl<R: BufRead> Iterator for LengthPrefixed<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.0.fill_buf() {
+            Ok([]) => return None,
+            Ok(_) => {}
+            Err(err) => return Some(Err(err)),
+        }
+        let mut len = [0; 4];
+        if let Err(err) = self.0.read_exact(&mut len) {
+            return Some(Err(err));
+        }
+        let len = u64::from(u32::from_be_bytes(len));
+        let mut record = Vec::new();
+        Some(match self.0.by_ref().take(len).read_to_end(&mut record) {
+            Ok(read) if read as u64 == len => Ok(record),
+            Ok(_) => Err(io::ErrorKind::UnexpectedEof.into()),
+            Err(err) => Err(err),
+        })
+    }
+}
+
+/// Sends records until they run out, one cannot be read or nothing is receiving them, giving whether anything still
+/// is.
+fn send_all(
+    sender: &mpsc::Sender<io::Result<Record>>,
+    records: impl Iterator<Item = io::Result<Record>>,
+) -> bool {
+    for record in records {
+        let failed = record.is_err();
+        if sender.blocking_send(record).is_err() {
+            return false;
+        }
+        // Readers keep failing once they have, e.g. when a directory is read as a file.
+        if failed {
+            break;
+        }
+    }
+    true
+}
+
+/// Sends each line appended to the file, until nothing is receiving them.
+fn follow(path: &Path, separator: &[u8], sender: &mpsc::Sender<io::Result<Record>>) {
+    let name = file_name(path);
+    let mut reader = match File::open(path) {
+        Ok(file) => BufReader::new(file),
+        Err(err) => {
+            let _ = sender.blocking_send(Err(context(path, &err)));
+            return;
+        }
+    };
+    let mut position = reader.seek(SeekFrom::End(0)).unwrap();
+    let mut line = vec![];
+    loop {
+        match read_record(&mut reader, separator, &mut line) {
+            Ok(0) => {
+                // Lines are appended to whichever file has the name, which may have been replaced or truncated.
+                match fs::metadata(path) {
+                    Ok(metadata) if !same_file(reader.get_ref(), &metadata) => {
+                        if let Ok(file) = File::open(path) {
+                            (reader, position) = (BufReader::new(file), 0);
+                            continue;
+                        }
+                    }
+                    Ok(metadata) if metadata.len() < position => {
+                        position = reader.seek(SeekFrom::Start(0)).unwrap();
+                        continue;
+                    }
+                    _ => {}
+                }
+                std::thread::sleep(POLL_INTERVAL);
+            }
+            Ok(read) => {
+                position += read as u64;
+                if !ends(&mut line, separator) {
+                    continue;
+                }
+                let record = Ok(Record {
+                    data: std::mem::take(&mut line),
+                    file: Some(name.clone()),
+                    watched: None,
+                });
+                if sender.blocking_send(record).is_err() {
+                    return;
+                }
+            }
+            Err(err) => {
+                let _ = sender.blocking_send(Err(context(path, &err)));
+                return;
+            }
+        }
+    }
+}
+
+/// Sends the content of each file appearing in the directory once it stops growing, until nothing is receiving them.
+fn watch(dir: &Path, sender: &mpsc::Sender<io::Result<Record>>) {
+    // The sizes of files seen at the previous poll, and the files already sent.
+    let (mut sizes, mut sent) = (HashMap::new(), HashSet::new());
+    loop {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(err) => {
+                let _ = sender.blocking_send(Err(context(dir, &err)));
+                return;
+            }
+        };
+        let mut present = HashSet::new();
+        for entry in entries.filter_map(Result::ok) {
+            let path = entry.path();
+            let hidden = entry.file_name().to_string_lossy().starts_with('.');
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            if hidden || !metadata.is_file() {
+                continue;
+            }
+            present.insert(path.clone());
+            let stable = sizes.insert(path.clone(), metadata.len()) == Some(metadata.len());
+            if !stable || sent.contains(&path) {
+                continue;
+            }
+            let record = fs::read(&path)
+                .map(|data| Record {
+                    data,
+                    file: Some(file_name(&path)),
+                    watched: Some(path.clone()),
+                })
+                .map_err(|err| context(&path, &err));
+            if sender.blocking_send(record).is_err() {
+                return;
+            }
+            sent.insert(path);
+        }
+        // Files which are gone may be replaced by new ones of the same name.
+        sizes.retain(|path, _| present.contains(path));
+        sent.retain(|path| present.contains(path));
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// How long to wait before checking followed files and watched directories for changes.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Whether the open file is the one with the given metadata.
+#[cfg(target_family = "unix")]
+fn same_file(file: &File, metadata: &Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    file.metadata()
+        .is_ok_and(|open| open.dev() == metadata.dev() && open.ino() == metadata.ino())
+}
+
+/// Files cannot be told apart on this platform, so rotation is only noticed when the file shrinks.
+#[cfg(not(target_family = "unix"))]
+fn same_file(_file: &File, _metadata: &Metadata) -> bool {
+    true
+}
+
+/// The name of the file without its directory.
+fn file_name(path: &Path) -> Arc<str> {
+    path.file_name()
+        .map_or_else(|| path.to_string_lossy(), |name| name.to_string_lossy())
+        .into()
+}
+
+/// Names the file an error occurred in.
+fn context(path: &Path, err: &io::Error) -> io::Error {
+    io::Error::new(err.kind(), format!("{}: {err}", path.display()))
+}
+
+/// Waits for the next record, giving none at the end of input or once the deadline has passed.
+pub async fn next(records: &mut Records, deadline: Option<Instant>) -> Option<io::Result<Record>> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, records.recv())
+            .await
+            .ok()
+            .flatten(),
+        None => records.recv().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LengthPrefixed, Separated};
+    use std::io;
+
+    fn separated(input: &str, separator: &str) -> Vec<String> {
+        Separated {
+            reader: input.as_bytes(),
+            separator: separator.as_bytes(),
+        }
+        .map(|record| String::from_utf8(record.unwrap()).unwrap())
+        .collect()
+    }
+
+    #[test]
+    fn splits_on_multi_byte_separators() {
+        assert_eq!(
+            separated("one\ntwo\n--\nthree", "\n--\n"),
+            ["one\ntwo", "three"]
+        );
+        assert_eq!(separated("a|b||c|||", "||"), ["a|b", "c", "|"]);
+        assert_eq!(separated("a||", "||"), ["a"]);
+    }
+
+    #[test]
+    fn strips_carriage_returns_of_windows_line_endings() {
+        assert_eq!(separated("a\r\nb\nc\r\n", "\n"), ["a", "b", "c"]);
+        assert_eq!(separated("a\r\nb\r\n", "\r\n"), ["a", "b"]);
+        assert_eq!(separated("a\r|b", "|"), ["a\r", "b"]);
+    }
+
+    #[test]
+    fn keeps_a_last_record_without_separator() {
+        assert_eq!(separated("a\nb", "\n"), ["a", "b"]);
+        assert_eq!(separated("", "\n"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn reports_truncated_length_prefixed_records() {
+        let input: &[u8] = &[0, 0, 0, 2, b'h', b'i', 0, 0, 0, 9, b'x'];
+        let mut records = LengthPrefixed(input);
+        assert_eq!(records.next().unwrap().unwrap(), b"hi");
+        let err = records.next().unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(records.next().is_none());
+    }
+}
@@ -0,0 +1,210 @@
+//! Totals describing a consume run.
+use core::time::Duration;
+use serde_json::json;
+use std::{
+    cell::{Cell, RefCell},
+    fs,
+    path::Path,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Counts of what happened to the messages consumed.
+pub struct Stats {
+    /// When consumption started.
+    started: Instant,
+
+    /// Number of messages received.
+    messages: Cell<u64>,
+
+    /// Total size of the bodies received.
+    bytes: Cell<u64>,
+
+    /// Number of messages acknowledged.
+    acks: Cell<u64>,
+
+    /// Number of messages rejected, whether dropped, dead lettered or requeued.
+    rejects: Cell<u64>,
+
+    /// Number of messages put back on the queue with a negative acknowledgement.
+    requeues: Cell<u64>,
+
+    /// Number of messages which could not be parsed, written out or executed.
+    errors: Cell<u64>,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            messages: Cell::default(),
+            bytes: Cell::default(),
+            acks: Cell::default(),
+            rejects: Cell::default(),
+            requeues: Cell::default(),
+            errors: Cell::default(),
+        }
+    }
+}
+
+impl Stats {
+    /// Counts a received message of the given size.
+    pub fn received(&self, len: usize) {
+        increment(&self.messages, 1);
+        increment(&self.bytes, len as u64);
+    }
+
+    /// Counts an acknowledged message.
+    pub fn acked(&self) {
+        increment(&self.acks, 1);
+    }
+
+    /// Counts a rejected message.
+    pub fn rejected(&self) {
+        increment(&self.rejects, 1);
+    }
+
+    /// Counts a requeued message.
+    pub fn requeued(&self) {
+        increment(&self.requeues, 1);
+    }
+
+    /// Counts a message which failed.
+    pub fn failed(&self) {
+        increment(&self.errors, 1);
+    }
+
+    /// Writes the totals as a json object to the file, or as a line to stderr.
+    pub fn report(&self, file: Option<&Path>) {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        #[allow(clippy::cast_precision_loss)]
+        let rate = self.messages.get() as f64 / elapsed;
+        if let Some(file) = file {
+            let summary = json!({
+                "messages": self.messages.get(),
+                "bytes": self.bytes.get(),
+                "acks": self.acks.get(),
+                "rejects": self.rejects.get(),
+                "requeues": self.requeues.get(),
+                "errors": self.errors.get(),
+                "elapsed_secs": elapsed,
+                "rate": rate,
+            });
+            fs::write(file, format!("{summary}\n")).unwrap();
+        } else {
+            eprintln!(
+                "{} messages, {} bytes, {} acked, {} rejected, {} requeued, {} errors in {elapsed:.3}s, {rate:.1} messages/s",
+                self.messages.get(),
+                self.bytes.get(),
+                self.acks.get(),
+                self.rejects.get(),
+                self.requeues.get(),
+                self.errors.get(),
+            );
+        }
+    }
+}
+
+/// How often progress is reported.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Measures throughput since it was last reported.
+pub struct Progress {
+    /// When progress was last reported.
+    since: Cell<Instant>,
+
+    /// Number of messages since then.
+    messages: Cell<u64>,
+
+    /// Total size of the bodies since then.
+    bytes: Cell<u64>,
+}
+
+impl Default for Progress {
+    fn default() -> Self {
+        Self {
+            since: Cell::new(Instant::now()),
+            messages: Cell::default(),
+            bytes: Cell::default(),
+        }
+    }
+}
+
+impl Progress {
+    /// Counts a message of the given size.
+    pub fn record(&self, len: usize) {
+        increment(&self.messages, 1);
+        increment(&self.bytes, len as u64);
+    }
+
+    /// Whether it is time to report progress again.
+    pub fn due(&self) -> bool {
+        self.since.get().elapsed() >= PROGRESS_INTERVAL
+    }
+
+    /// Prints the rates since the last report to stderr along with the backlog, if known, and starts measuring afresh.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn report(&self, backlog: Option<u64>) {
+        let elapsed = self.since.get().elapsed().as_secs_f64();
+        let messages = self.messages.take() as f64 / elapsed;
+        let bytes = self.bytes.take() as f64 / elapsed;
+        self.since.set(Instant::now());
+        match backlog {
+            Some(backlog) => {
+                eprintln!("{messages:.1} messages/s, {bytes:.0} bytes/s, {backlog} ready");
+            }
+            None => eprintln!("{messages:.1} messages/s, {bytes:.0} bytes/s"),
+        }
+    }
+}
+
+/// Time taken from publishing to consuming each message.
+#[derive(Default)]
+pub struct Latency {
+    /// Latency of each message so far in milliseconds.
+    samples: RefCell<Vec<u64>>,
+}
+
+impl Latency {
+    /// Records the latency of a message published at the given unix time in milliseconds, returning it.
+    pub fn record(&self, published: u64) -> u64 {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let now = u64::try_from(now.as_millis()).unwrap_or(u64::MAX);
+        let latency = now.saturating_sub(published);
+        self.samples.borrow_mut().push(latency);
+        latency
+    }
+
+    /// Prints percentiles and a histogram with power of two buckets to stderr.
+    pub fn report(&self) {
+        let mut samples = self.samples.borrow_mut();
+        if samples.is_empty() {
+            eprintln!("latency: no timestamped messages");
+            return;
+        }
+        samples.sort_unstable();
+        let percentile = |p: usize| samples[(samples.len() - 1) * p / 100];
+        #[allow(clippy::cast_precision_loss)]
+        let mean = samples.iter().sum::<u64>() as f64 / samples.len() as f64;
+        eprintln!(
+            "latency: {} messages, min {}ms, mean {mean:.1}ms, p50 {}ms, p95 {}ms, p99 {}ms, max {}ms",
+            samples.len(),
+            samples[0],
+            percentile(50),
+            percentile(95),
+            percentile(99),
+            samples[samples.len() - 1],
+        );
+        let mut start = 0;
+        while start < samples.len() {
+            let bound = samples[start].saturating_add(1).next_power_of_two();
+            let count = samples[start..].partition_point(|&sample| sample < bound);
+            eprintln!("  < {bound}ms: {count}");
+            start += count;
+        }
+    }
+}
+
+/// Adds to the counter.
+fn increment(counter: &Cell<u64>, by: u64) {
+    counter.set(counter.get() + by);
+}
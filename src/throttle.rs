@@ -0,0 +1,54 @@
+//! Limiting how fast messages are published.
+use core::time::Duration;
+use std::cell::Cell;
+use tokio::time::Instant;
+
+/// A token bucket holding up to a tenth of a second worth of messages, so bursts stay short.
+pub struct Throttle {
+    /// Messages allowed per second.
+    rate: f64,
+
+    /// Most tokens the bucket holds.
+    burst: f64,
+
+    /// Tokens left, negative when messages are waiting for them.
+    tokens: Cell<f64>,
+
+    /// When the tokens were last counted.
+    updated: Cell<Instant>,
+}
+
+impl Throttle {
+    /// Allows the given number of messages per second.
+    pub fn new(rate: f64) -> Self {
+        let burst = (rate / 10.0).max(1.0);
+        Self {
+            rate,
+            burst,
+            tokens: Cell::new(burst),
+            updated: Cell::new(Instant::now()),
+        }
+    }
+
+    /// Waits until the next message may be published.
+    pub async fn wait(&self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated.get()).as_secs_f64();
+        let tokens = (self.tokens.get() + elapsed * self.rate).min(self.burst) - 1.0;
+        self.tokens.set(tokens);
+        self.updated.set(now);
+        if tokens < 0.0 {
+            tokio::time::sleep(Duration::from_secs_f64(-tokens / self.rate)).await;
+        }
+    }
+}
+
+/// Parses a positive number of messages per second.
+pub fn parse_rate(rate: &str) -> Result<f64, String> {
+    match rate.parse() {
+        Ok(rate) if rate > 0.0 && f64::is_finite(rate) => Ok(rate),
+        _ => Err(format!(
+            "expected a positive number of messages per second: {rate}"
+        )),
+    }
+}
@@ -0,0 +1,203 @@
+//! Declarative topology files, using the same layout as rabbitmq definitions exports.
+use crate::{input::amqp_value, management::ManagementOpts, parse_exchange_kind};
+use amq_protocol_types::FieldTable;
+use lapin::{
+    options::{ExchangeBindOptions, ExchangeDeclareOptions, QueueBindOptions, QueueDeclareOptions},
+    Channel,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{stdout, Write},
+    path::PathBuf,
+};
+use structopt::StructOpt;
+
+/// Topology management commands.
+#[derive(StructOpt)]
+pub enum TopologyCmd {
+    /// Idempotently declares the exchanges, queues and bindings described in a yaml or json file.
+    Apply {
+        /// The topology file.
+        file: PathBuf,
+    },
+
+    /// Writes the exchanges, queues and bindings of a vhost, as reported by the management api.
+    Export {
+        #[structopt(flatten)]
+        management: ManagementOpts,
+
+        /// Whether to write json instead of yaml.
+        #[structopt(short, long)]
+        json: bool,
+
+        /// Output file, defaults to stdout.
+        #[structopt(short, long)]
+        output: Option<PathBuf>,
+    },
+}
+
+impl TopologyCmd {
+    /// Runs the topology command.
+    pub async fn run(self, chan: Channel) {
+        match self {
+            Self::Apply { file } => {
+                let topology: Topology =
+                    serde_yaml::from_reader(File::open(file).unwrap()).unwrap();
+                topology.apply(&chan).await;
+            }
+            Self::Export {
+                management,
+                json,
+                output,
+            } => {
+                let topology: Topology = management.get("definitions");
+                let mut out: Box<dyn Write> = match output {
+                    Some(path) => Box::new(File::create(path).unwrap()),
+                    None => Box::new(stdout()),
+                };
+                if json {
+                    serde_json::to_writer_pretty(&mut out, &topology).unwrap();
+                    writeln!(out).unwrap();
+                } else {
+                    serde_yaml::to_writer(out, &topology).unwrap();
+                }
+            }
+        }
+    }
+}
+
+/// Exchanges, queues and bindings making up a topology.
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default)]
+struct Topology {
+    exchanges: Vec<Exchange>,
+    queues: Vec<Queue>,
+    bindings: Vec<Binding>,
+}
+
+/// An exchange definition.
+#[derive(Deserialize, Serialize)]
+struct Exchange {
+    name: String,
+    #[serde(rename = "type", default = "default_exchange_type")]
+    kind: String,
+    #[serde(default)]
+    durable: bool,
+    #[serde(default)]
+    auto_delete: bool,
+    #[serde(default)]
+    internal: bool,
+    #[serde(default)]
+    arguments: Arguments,
+}
+
+/// A queue definition.
+#[derive(Deserialize, Serialize)]
+struct Queue {
+    name: String,
+    #[serde(default)]
+    durable: bool,
+    #[serde(default)]
+    auto_delete: bool,
+    #[serde(default)]
+    arguments: Arguments,
+}
+
+/// A binding from a source exchange to a destination queue or exchange.
+#[derive(Deserialize, Serialize)]
+struct Binding {
+    source: String,
+    destination: String,
+    #[serde(default)]
+    destination_type: DestinationType,
+    #[serde(default)]
+    routing_key: String,
+    #[serde(default)]
+    arguments: Arguments,
+}
+
+/// What a binding routes messages to.
+#[derive(Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum DestinationType {
+    #[default]
+    Queue,
+    Exchange,
+}
+
+/// Optional x-arguments in their json representation.
+type Arguments = BTreeMap<String, serde_json::Value>;
+
+/// Exchanges route directly unless specified otherwise.
+fn default_exchange_type() -> String {
+    "direct".into()
+}
+
+impl Topology {
+    /// Declares everything in the topology, exchanges first so bindings can refer to them.
+    async fn apply(self, chan: &Channel) {
+        for exchange in self.exchanges {
+            chan.exchange_declare(
+                &exchange.name,
+                parse_exchange_kind(&exchange.kind),
+                ExchangeDeclareOptions {
+                    durable: exchange.durable,
+                    auto_delete: exchange.auto_delete,
+                    internal: exchange.internal,
+                    ..ExchangeDeclareOptions::default()
+                },
+                arguments_table(&exchange.arguments),
+            )
+            .await
+            .unwrap();
+        }
+        for queue in self.queues {
+            chan.queue_declare(
+                &queue.name,
+                QueueDeclareOptions {
+                    durable: queue.durable,
+                    auto_delete: queue.auto_delete,
+                    ..QueueDeclareOptions::default()
+                },
+                arguments_table(&queue.arguments),
+            )
+            .await
+            .unwrap();
+        }
+        for binding in self.bindings {
+            let args = arguments_table(&binding.arguments);
+            match binding.destination_type {
+                DestinationType::Queue => chan
+                    .queue_bind(
+                        &binding.destination,
+                        &binding.source,
+                        &binding.routing_key,
+                        QueueBindOptions::default(),
+                        args,
+                    )
+                    .await
+                    .unwrap(),
+                DestinationType::Exchange => chan
+                    .exchange_bind(
+                        &binding.destination,
+                        &binding.source,
+                        &binding.routing_key,
+                        ExchangeBindOptions::default(),
+                        args,
+                    )
+                    .await
+                    .unwrap(),
+            }
+        }
+    }
+}
+
+/// Converts json arguments into a field table.
+fn arguments_table(args: &Arguments) -> FieldTable {
+    args.iter()
+        .map(|(key, value)| (key.as_str().into(), amqp_value(value.clone())))
+        .collect::<BTreeMap<_, _>>()
+        .into()
+}
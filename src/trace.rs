@@ -0,0 +1,117 @@
+//! Watching the firehose of messages published and delivered on a vhost.
+use crate::{management::ManagementOpts, output::json_value};
+use amq_protocol_types::FieldTable;
+use futures_lite::stream::StreamExt;
+use lapin::{
+    options::{BasicConsumeOptions, QueueBindOptions, QueueDeclareOptions},
+    protocol::constants::REPLY_SUCCESS,
+    Channel,
+};
+use serde_json::{json, Map, Value};
+use structopt::StructOpt;
+
+/// The exchange which traced messages are published to.
+const TRACE_EXCHANGE: &str = "amq.rabbitmq.trace";
+
+/// Options for consuming traced messages.
+#[derive(StructOpt)]
+pub struct Trace {
+    /// Either publish or deliver, to only show messages as they are published to exchanges or delivered from queues.
+    #[structopt(long, possible_values = &["publish", "deliver"])]
+    only: Option<String>,
+
+    /// Turns on tracing for the vhost via the management api first, it stays on afterwards.
+    #[structopt(long)]
+    enable: bool,
+
+    #[structopt(flatten)]
+    management: ManagementOpts,
+
+    /// Whether to print each event on a single line.
+    #[structopt(long)]
+    compact: bool,
+
+    /// Exits after this many events have been printed.
+    #[structopt(long)]
+    count: Option<u64>,
+}
+
+impl Trace {
+    /// Binds a temporary queue to the trace exchange and prints each event as a json object.
+    pub async fn run(self, chan: Channel) {
+        if self.enable {
+            self.management.put("vhosts", &json!({ "tracing": true }));
+        }
+        let queue = chan
+            .queue_declare(
+                "",
+                QueueDeclareOptions {
+                    exclusive: true,
+                    auto_delete: true,
+                    ..QueueDeclareOptions::default()
+                },
+                FieldTable::default(),
+            )
+            .await
+            .unwrap();
+        let events = match self.only.as_deref() {
+            Some(event) => vec![event],
+            None => vec!["publish", "deliver"],
+        };
+        for event in events {
+            chan.queue_bind(
+                queue.name().as_str(),
+                TRACE_EXCHANGE,
+                &format!("{event}.#"),
+                QueueBindOptions::default(),
+                FieldTable::default(),
+            )
+            .await
+            .unwrap();
+        }
+        let mut consumer = chan
+            .basic_consume(
+                queue.name().as_str(),
+                "",
+                BasicConsumeOptions {
+                    no_ack: true,
+                    ..BasicConsumeOptions::default()
+                },
+                FieldTable::default(),
+            )
+            .await
+            .unwrap();
+        let mut remaining = self.count;
+        while remaining != Some(0) {
+            let Some(delivery) = consumer.next().await else {
+                break;
+            };
+            let delivery = delivery.unwrap();
+            let (event, name) = delivery
+                .routing_key
+                .as_str()
+                .split_once('.')
+                .unwrap_or((delivery.routing_key.as_str(), ""));
+            let mut record = Map::new();
+            record.insert("event".into(), event.into());
+            record.insert("name".into(), name.into());
+            if let Some(headers) = delivery.properties.headers() {
+                for (key, value) in headers.inner() {
+                    record.insert(key.to_string(), json_value(value));
+                }
+            }
+            let body = String::from_utf8_lossy(&delivery.data);
+            record.insert("body".into(), body.into());
+            let record = Value::Object(record);
+            if self.compact {
+                println!("{record}");
+            } else {
+                println!("{}", serde_json::to_string_pretty(&record).unwrap());
+            }
+            if let Some(remaining) = &mut remaining {
+                *remaining -= 1;
+            }
+        }
+        chan.close(REPLY_SUCCESS, "OK").await.unwrap();
+    }
+}